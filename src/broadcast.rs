@@ -0,0 +1,154 @@
+use crate::job::SendPtr;
+use crate::pool::{current_worker, Pool, Task};
+
+use parking_lot::{Condvar, Mutex};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+/// Tells a broadcast closure which worker it runs on
+#[derive(Debug, Clone, Copy)]
+pub struct BroadcastContext {
+    index: usize,
+    num_threads: usize,
+}
+
+impl BroadcastContext {
+    /// Index of the worker thread, in `0..num_threads`
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    #[inline]
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+}
+
+impl Pool {
+    /// Runs `func` exactly once on every worker thread and returns the
+    /// results ordered by worker index. Waits for workers busy with other
+    /// work. Called from a worker of this pool, that worker's share runs
+    /// inline, but two workers broadcasting at once wait on each other.
+    /// A panic in `func` is resumed here once every worker finished.
+    pub fn broadcast<F, R>(&self, func: F) -> Vec<R>
+    where
+        F: Fn(BroadcastContext) -> R + Sync,
+        R: Send,
+    {
+        let num_threads = self.num_threads;
+        let mut results: Vec<Option<thread::Result<R>>> = (0..num_threads).map(|_| None).collect();
+        let ptr = SendPtr(results.as_mut_ptr());
+        let remaining = Arc::new((Mutex::new(num_threads), Condvar::new()));
+        let func = &func;
+        let current = current_worker(self.id);
+        let mut inline = None;
+
+        for index in 0..num_threads {
+            let remaining = remaining.clone();
+            let task: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+                let ctx = BroadcastContext { index, num_threads };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| func(ctx)));
+                // Every worker writes its own slot
+                *unsafe { ptr.get(index) } = Some(result);
+
+                let (lock, cvar) = &*remaining;
+                let mut remaining = lock.lock();
+                *remaining -= 1;
+                if *remaining == 0 {
+                    cvar.notify_all();
+                }
+            });
+            // Nothing borrowed is touched after the count drops, which
+            // this frame waits for
+            if Some(index) == current {
+                // This worker would only get to its task after we return
+                inline = Some(task);
+                continue;
+            }
+            let task: Task = unsafe { mem::transmute(task) };
+            self.send_task_to(index, task);
+        }
+        if let Some(task) = inline {
+            task();
+        }
+
+        {
+            let (lock, cvar) = &*remaining;
+            let mut remaining = lock.lock();
+            while *remaining > 0 {
+                cvar.wait(&mut remaining);
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| match result {
+                Some(Ok(result)) => result,
+                Some(Err(payload)) => panic::resume_unwind(payload),
+                None => unreachable!("broadcast finished without a result"),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_broadcast() {
+        thread_local!(static COUNTER: Cell<usize> = const { Cell::new(0) });
+
+        let pool = Pool::new(4);
+        let names = pool.broadcast(|ctx| {
+            assert_eq!(ctx.num_threads(), 4);
+            COUNTER.with(|counter| counter.set(ctx.index() * 10));
+            (ctx.index(), thread::current().name().unwrap().to_string())
+        });
+        assert_eq!(names.len(), 4);
+        for (worker, (index, name)) in names.iter().enumerate() {
+            assert_eq!(*index, worker);
+            assert_eq!(*name, format!("Worker thread {}", worker));
+        }
+
+        // Thread local state set by one broadcast is seen by the next
+        let counters = pool.broadcast(|_| COUNTER.with(Cell::get));
+        assert_eq!(counters, vec![0, 10, 20, 30]);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.broadcast(|ctx| {
+                if ctx.index() == 2 {
+                    panic!("worker failed");
+                }
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(pool.broadcast(|ctx| ctx.index()), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_broadcast_from_worker() {
+        let pool = Arc::new(Pool::new(3));
+        let inner = pool.clone();
+        let handle = pool.spawn(move || {
+            let caller = thread::current().name().unwrap().to_string();
+            let names = inner.broadcast(|_| thread::current().name().unwrap().to_string());
+            (caller, names)
+        });
+        let (caller, names) = handle.join().unwrap();
+        assert_eq!(
+            names,
+            vec!["Worker thread 0", "Worker thread 1", "Worker thread 2"]
+        );
+        assert!(names.contains(&caller));
+
+        // Workers of another pool are not special
+        let other = Pool::new(2);
+        let indices = pool.spawn(move || other.broadcast(|ctx| ctx.index()));
+        assert_eq!(indices.join().unwrap(), vec![0, 1]);
+    }
+}
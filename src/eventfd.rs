@@ -0,0 +1,75 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::task::{Wake, Waker};
+
+/// Owned eventfd, closed once the last reference is gone
+#[derive(Debug)]
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl Wake for Fd {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let one: u64 = 1;
+        // Can only fail once the counter is about to overflow, then it's readable anyway
+        unsafe { libc::write(self.0, &one as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+/// Non-blocking Linux eventfd which becomes readable once a dispatch
+/// completed. Meant to be registered with epoll next to other fds.
+#[derive(Debug)]
+pub struct EventFd {
+    fd: Arc<Fd>,
+}
+
+impl EventFd {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: Arc::new(Fd(fd)),
+        })
+    }
+
+    /// Waker which signals the fd, it keeps the fd open until dropped
+    #[inline]
+    pub(crate) fn waker(&self) -> Waker {
+        Waker::from(self.fd.clone())
+    }
+
+    /// Resets the fd. Fails with WouldBlock if it was not signalled.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut count: u64 = 0;
+        let read = unsafe {
+            libc::read(
+                self.fd.0,
+                &mut count as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(count)
+    }
+}
+
+impl AsRawFd for EventFd {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.0
+    }
+}
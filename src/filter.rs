@@ -0,0 +1,222 @@
+use crate::job::{Job, SendPtr};
+use crate::pool::Pool;
+
+use std::ptr;
+
+/// Which elements are kept and where each block starts writing them
+struct Compaction {
+    blocks: Vec<(usize, usize)>,
+    keep: Vec<bool>,
+    offsets: Vec<usize>,
+    kept: usize,
+}
+
+impl Pool {
+    /// Returns clones of the elements matching `pred`, in order
+    #[inline]
+    pub fn filter<A, P>(&self, inputs: &[A], pred: P) -> Vec<A>
+    where
+        A: Clone + Send + Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        let compaction = self.compaction(inputs, pred);
+        let mut outs: Vec<A> = Vec::with_capacity(compaction.kept);
+        let outs_ptr = SendPtr(outs.as_mut_ptr());
+
+        self.dispatch_fn(compaction.blocks.len(), |block| {
+            let (count, offset) = compaction.blocks[block];
+            let range = offset..offset + count;
+            let mut dst = compaction.offsets[block];
+            for (input, &keep) in inputs[range.clone()].iter().zip(&compaction.keep[range]) {
+                if keep {
+                    // Every block writes its own range of outs
+                    unsafe { ptr::write(outs_ptr.0.add(dst), input.clone()) };
+                    dst += 1;
+                }
+            }
+        });
+
+        unsafe { outs.set_len(compaction.kept) };
+        outs
+    }
+
+    /// Returns clones of the elements matching `pred` and of the ones which
+    /// don't, both in order
+    #[inline]
+    pub fn partition<A, P>(&self, inputs: &[A], pred: P) -> (Vec<A>, Vec<A>)
+    where
+        A: Clone + Send + Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        let compaction = self.compaction(inputs, pred);
+        let mut matched: Vec<A> = Vec::with_capacity(compaction.kept);
+        let mut rest: Vec<A> = Vec::with_capacity(inputs.len() - compaction.kept);
+        let matched_ptr = SendPtr(matched.as_mut_ptr());
+        let rest_ptr = SendPtr(rest.as_mut_ptr());
+
+        self.dispatch_fn(compaction.blocks.len(), |block| {
+            let (count, offset) = compaction.blocks[block];
+            let mut matched_dst = compaction.offsets[block];
+            let mut rest_dst = offset - matched_dst;
+            let range = offset..offset + count;
+            for (input, &keep) in inputs[range.clone()].iter().zip(&compaction.keep[range]) {
+                // Every block writes its own range of both outputs
+                unsafe {
+                    if keep {
+                        ptr::write(matched_ptr.0.add(matched_dst), input.clone());
+                        matched_dst += 1;
+                    } else {
+                        ptr::write(rest_ptr.0.add(rest_dst), input.clone());
+                        rest_dst += 1;
+                    }
+                }
+            }
+        });
+
+        unsafe {
+            matched.set_len(compaction.kept);
+            rest.set_len(inputs.len() - compaction.kept);
+        }
+        (matched, rest)
+    }
+
+    /// Keeps only the elements matching `pred`, in order, see Vec::retain.
+    /// Compacts in place without allocating a second buffer.
+    #[inline]
+    pub fn retain<A, P>(&self, elems: &mut Vec<A>, pred: P)
+    where
+        A: Send + Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        let compaction = self.compaction(elems, pred);
+        let elems_ptr = SendPtr(elems.as_mut_ptr());
+
+        // Elements are moved or dropped below so elems must not own them
+        unsafe { elems.set_len(0) };
+
+        // Every block packs its kept elements at the start of its own range
+        self.dispatch_fn(compaction.blocks.len(), |block| {
+            let (count, offset) = compaction.blocks[block];
+            let mut dst = offset;
+            for index in offset..offset + count {
+                unsafe {
+                    if compaction.keep[index] {
+                        if dst != index {
+                            ptr::copy_nonoverlapping(
+                                elems_ptr.0.add(index),
+                                elems_ptr.0.add(dst),
+                                1,
+                            );
+                        }
+                        dst += 1;
+                    } else {
+                        ptr::drop_in_place(elems_ptr.0.add(index));
+                    }
+                }
+            }
+        });
+
+        // Then the packed runs slide down in order, a run may overlap the
+        // range of the run before it so this can't be done in parallel
+        for (block, &(_, offset)) in compaction.blocks.iter().enumerate() {
+            let dst = compaction.offsets[block];
+            let kept = compaction
+                .offsets
+                .get(block + 1)
+                .unwrap_or(&compaction.kept)
+                - dst;
+            if dst != offset {
+                unsafe { ptr::copy(elems_ptr.0.add(offset), elems_ptr.0.add(dst), kept) };
+            }
+        }
+
+        unsafe { elems.set_len(compaction.kept) };
+    }
+
+    /// Evaluates `pred` on every input, counting the matches of each batch
+    /// range, then scans the counts into the offset each block writes to.
+    fn compaction<A, P>(&self, inputs: &[A], pred: P) -> Compaction
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        let blocks: Vec<(usize, usize)> = Job::batch_ranges(inputs.len(), self.num_threads)
+            .filter(|&(count, _)| count > 0)
+            .collect();
+        let mut keep = vec![false; inputs.len()];
+        let mut offsets = vec![0; blocks.len()];
+
+        let keep_ptr = SendPtr(keep.as_mut_ptr());
+        let offsets_ptr = SendPtr(offsets.as_mut_ptr());
+        self.dispatch_fn(blocks.len(), |block| {
+            let (count, offset) = blocks[block];
+            // Blocks never overlap and every block index is handed out once
+            let keep = unsafe { std::slice::from_raw_parts_mut(keep_ptr.0.add(offset), count) };
+            let mut kept = 0;
+            for (input, keep) in inputs[offset..offset + count].iter().zip(keep) {
+                *keep = pred(input);
+                kept += *keep as usize;
+            }
+            unsafe { *offsets_ptr.get(block) = kept };
+        });
+
+        let mut kept = 0;
+        for offset in offsets.iter_mut() {
+            let count = *offset;
+            *offset = kept;
+            kept += count;
+        }
+
+        Compaction {
+            blocks,
+            keep,
+            offsets,
+            kept,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_partition() {
+        let pool = Pool::new(3);
+        let array: Vec<String> = (0..10_000).map(|i| i.to_string()).collect();
+        let is_even = |val: &String| val.ends_with(&['0', '2', '4', '6', '8'][..]);
+
+        let evens = pool.filter(&array, is_even);
+        let expected: Vec<String> = array.iter().filter(|val| is_even(val)).cloned().collect();
+        assert_eq!(evens, expected);
+
+        let (evens, odds) = pool.partition(&array, is_even);
+        assert_eq!(evens, expected);
+        assert_eq!(
+            odds,
+            array
+                .iter()
+                .filter(|val| !is_even(val))
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+
+        assert!(pool.filter(&array[..0], is_even).is_empty());
+    }
+
+    #[test]
+    fn test_retain() {
+        let pool = Pool::new(3);
+        let mut array: Vec<String> = (0..10_000).map(|i| i.to_string()).collect();
+        let mut expected = array.clone();
+        expected.retain(|val| val.contains('7'));
+
+        let buffer = array.as_ptr();
+        pool.retain(&mut array, |val| val.contains('7'));
+        assert_eq!(array, expected);
+        assert_eq!(array.as_ptr(), buffer);
+
+        pool.retain(&mut array, |_| false);
+        assert!(array.is_empty());
+    }
+}
@@ -0,0 +1,377 @@
+#[cfg(target_os = "linux")]
+use crate::eventfd::EventFd;
+use crate::job::{Job, SendPtr};
+use crate::pool::{current_worker, Message, Pool, SendRef};
+use crate::task::{JoinHandle, Packet};
+
+use parking_lot::Mutex;
+use std::future::Future;
+#[cfg(target_os = "linux")]
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// Elements owned by an async dispatch. The job writes them through `ptr`
+/// and keeps them alive if the future is dropped before completion.
+struct OwnedElems<A> {
+    elems: Mutex<Vec<A>>,
+    ptr: SendPtr<A>,
+}
+
+/// Resolves to the elements of Pool::dispatch_async once every element
+/// was processed. Dropping it does not stop the dispatch. A panic of the
+/// closure is resumed by the poll which sees the dispatch complete.
+pub struct DispatchFuture<A> {
+    job: Arc<Job>,
+    owned: Arc<OwnedElems<A>>,
+    pool_id: usize,
+    #[cfg(target_os = "linux")]
+    event: Option<EventFd>,
+}
+
+impl<A> DispatchFuture<A> {
+    /// Eventfd which becomes readable once the dispatch completed, so an
+    /// epoll loop knows when to poll the future. Created on the first
+    /// call, later calls return the same fd. Polls from an executor keep
+    /// signalling it, but creating it after such a poll drops the
+    /// executor's waker.
+    #[cfg(target_os = "linux")]
+    pub fn eventfd(&mut self) -> io::Result<&EventFd> {
+        if self.event.is_none() {
+            let event = EventFd::new()?;
+            let waker = event.waker();
+            // Signals right away if the dispatch already completed
+            if let Poll::Ready(()) = self.job.poll_complete(&mut Context::from_waker(&waker)) {
+                waker.wake();
+            }
+            self.event = Some(event);
+        }
+        Ok(self.event.as_ref().unwrap())
+    }
+}
+
+impl<A> Future for DispatchFuture<A> {
+    type Output = Vec<A>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<A>> {
+        // A worker blocking on the future, e.g. in block_on, would otherwise
+        // never get to the job queued behind it
+        if current_worker(self.pool_id).is_some() {
+            self.job.execute();
+        }
+        #[cfg(target_os = "linux")]
+        let poll = match self.event {
+            // The job keeps one waker, it has to signal the fd as well
+            Some(ref event) => {
+                let waker = Waker::from(Arc::new(WakeBoth(event.waker(), cx.waker().clone())));
+                self.job.poll_complete(&mut Context::from_waker(&waker))
+            }
+            None => self.job.poll_complete(cx),
+        };
+        #[cfg(not(target_os = "linux"))]
+        let poll = self.job.poll_complete(cx);
+        match poll {
+            Poll::Ready(()) => {
+                if let Some(payload) = self.job.take_panic() {
+                    panic::resume_unwind(payload);
+                }
+                // No worker touches the elements once the job completed
+                Poll::Ready(mem::take(&mut *self.owned.elems.lock()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Wakes the eventfd of a DispatchFuture and the task polling it
+#[cfg(target_os = "linux")]
+struct WakeBoth(Waker, Waker);
+
+#[cfg(target_os = "linux")]
+impl Wake for WakeBoth {
+    fn wake(self: Arc<Self>) {
+        self.0.wake_by_ref();
+        self.1.wake_by_ref();
+    }
+}
+
+/// Future spawned on the pool. Every wake queues it once on the worker
+/// it was spawned on, in between the jobs sent to that worker.
+struct FutureTask<F: Future> {
+    future: Mutex<Option<Pin<Box<F>>>>,
+    packet: Arc<Packet<F::Output>>,
+    /// Set while the task is queued and not polled yet
+    scheduled: AtomicBool,
+    sender: SendRef,
+}
+
+impl<F> FutureTask<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn schedule(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            let sender = self.sender.clone();
+            // Once the workers are gone the future can't make progress anyway
+            let _ = sender.send(Message::Task(Box::new(move || self.run())));
+        }
+    }
+
+    fn run(self: Arc<Self>) {
+        // Wakes from here on queue another poll
+        self.scheduled.store(false, Ordering::Release);
+        let waker = Waker::from(self.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut slot = self.future.lock();
+        let future = match slot.as_mut() {
+            Some(future) => future,
+            None => return,
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+            Ok(Poll::Pending) => {}
+            Ok(Poll::Ready(output)) => {
+                *slot = None;
+                self.packet.set(Ok(output));
+            }
+            Err(payload) => {
+                *slot = None;
+                self.packet.set(Err(payload));
+            }
+        }
+    }
+}
+
+impl<F> Wake for FutureTask<F>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+}
+
+/// Wakes a thread parked in Pool::block_on
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl Pool {
+    /// Runs `future` on the workers. It is polled by one worker, in
+    /// between the jobs of that worker, whenever it is woken. A panic
+    /// while polling is handed to JoinHandle::join.
+    pub fn spawn_future<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let packet = Packet::new();
+        let task = Arc::new(FutureTask {
+            future: Mutex::new(Some(Box::pin(future))),
+            packet: packet.clone(),
+            scheduled: AtomicBool::new(false),
+            sender: self.next_sender(),
+        });
+        task.schedule();
+        JoinHandle::new(packet)
+    }
+
+    /// Polls `future` on the calling thread, parking it while the future
+    /// is pending. Futures like dispatch_async still run on the workers.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// Like dispatch_mut but takes ownership of the elements and returns a
+    /// future instead of blocking. The future works with any executor.
+    pub fn dispatch_async<F, A>(&self, mut elems: Vec<A>, func: F) -> DispatchFuture<A>
+    where
+        A: Send + 'static,
+        F: Fn(&mut A) + Send + Sync + 'static,
+    {
+        let num = elems.len();
+        let owned = Arc::new(OwnedElems {
+            ptr: SendPtr(elems.as_mut_ptr()),
+            elems: Mutex::new(elems),
+        });
+
+        let job_owned = owned.clone();
+        // The job owns everything it touches, it may outlive the future
+        let job = unsafe {
+            Job::from_fn(
+                num,
+                move |index| func(job_owned.ptr.get(index)),
+                self.num_threads,
+            )
+        };
+        DispatchFuture {
+            job: self.submit(job),
+            owned,
+            pool_id: self.id,
+            #[cfg(target_os = "linux")]
+            event: None,
+        }
+    }
+
+    /// Like map but takes ownership of the inputs and returns a future
+    pub fn map_async<F, A, B>(&self, inputs: Vec<A>, func: F) -> impl Future<Output = Vec<B>>
+    where
+        A: Send + 'static,
+        B: Default + Send + 'static,
+        F: (Fn(&A) -> B) + Send + Sync + 'static,
+    {
+        let elems = inputs
+            .into_iter()
+            .map(|input| (input, B::default()))
+            .collect();
+        let dispatch = self.dispatch_async(elems, move |item: &mut (A, B)| {
+            item.1 = func(&item.0);
+        });
+        async move { dispatch.await.into_iter().map(|(_, out)| out).collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_dispatch_async() {
+        let pool = Pool::new(3);
+        let array = pool.block_on(pool.dispatch_async(vec![1usize; 10_000], |val| *val *= 3));
+        assert_eq!(array, vec![3usize; 10_000]);
+
+        let inputs: Vec<usize> = (0..10_000).collect();
+        let outputs = pool.block_on(pool.map_async(inputs, |val| val * 2));
+        assert_eq!(outputs, (0..10_000).map(|val| val * 2).collect::<Vec<_>>());
+
+        // A slow dispatch is pending first and completed through the waker
+        let array = pool.block_on(pool.dispatch_async(vec![0usize; 64], |val| {
+            thread::sleep(Duration::from_millis(1));
+            *val = 1;
+        }));
+        assert_eq!(array, vec![1usize; 64]);
+
+        // Dropping the future early leaves the elements to the job
+        drop(pool.dispatch_async(vec![String::new(); 1000], |val| val.push('x')));
+    }
+
+    #[test]
+    fn test_spawn_future() {
+        let pool = Arc::new(Pool::new(2));
+
+        // Spawned futures can await dispatches on the same pool
+        let inner = pool.clone();
+        let handle = pool.spawn_future(async move {
+            let array = inner
+                .dispatch_async(vec![1usize; 10_000], |val| *val += 1)
+                .await;
+            array.iter().sum::<usize>()
+        });
+        assert_eq!(handle.join().unwrap(), 20_000);
+
+        let handles: Vec<_> = (0..20usize)
+            .map(|i| pool.spawn_future(async move { i * i }))
+            .collect();
+        let squares: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(squares, (0..20).map(|i| i * i).collect::<Vec<_>>());
+
+        let panicked = pool.spawn_future(async { panic!("future failed") });
+        assert!(panicked.join().is_err());
+        let inner = pool.clone();
+        let panicked = pool.spawn_future(async move {
+            inner
+                .dispatch_async(vec![0usize; 100], |_| panic!("dispatch failed"))
+                .await
+        });
+        assert!(panicked.join().is_err());
+        assert_eq!(pool.block_on(async { 5 }), 5);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dispatch_async_eventfd() {
+        use std::os::unix::io::AsRawFd;
+        use std::sync::atomic::AtomicBool;
+
+        let pool = Pool::new(2);
+        let release = Arc::new(AtomicBool::new(false));
+        let wait = release.clone();
+        let mut future = pool.dispatch_async(vec![0usize; 1000], move |val| {
+            while !wait.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            *val = 1;
+        });
+
+        let event = future.eventfd().unwrap();
+        assert_eq!(
+            event.read().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        release.store(true, Ordering::Release);
+        let mut poll = libc::pollfd {
+            fd: event.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut poll, 1, 10_000) }, 1);
+        assert_eq!(event.read().unwrap(), 1);
+        assert_eq!(pool.block_on(future), vec![1usize; 1000]);
+
+        // A pending poll from an executor leaves the fd signalled too
+        let release = Arc::new(AtomicBool::new(false));
+        let wait = release.clone();
+        let mut future = pool.dispatch_async(vec![0usize; 1000], move |val| {
+            while !wait.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            *val = 2;
+        });
+        let fd = future.eventfd().unwrap().as_raw_fd();
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let pending = Pin::new(&mut future).poll(&mut Context::from_waker(&waker));
+        assert!(pending.is_pending());
+        release.store(true, Ordering::Release);
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut poll, 1, 10_000) }, 1);
+        assert_eq!(pool.block_on(future), vec![2usize; 1000]);
+    }
+
+    #[test]
+    fn test_block_on_worker() {
+        let pool = Arc::new(Pool::new(1));
+
+        // The only worker blocks on a dispatch it has to run itself
+        let inner = pool.clone();
+        let handle = pool.spawn(move || {
+            let array = inner.block_on(inner.dispatch_async(vec![1usize; 10_000], |val| *val *= 2));
+            array.iter().sum::<usize>()
+        });
+        assert_eq!(handle.join().unwrap(), 20_000);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::job::SendPtr;
+
+use std::marker::PhantomData;
+use std::slice;
+
+/// Mutable view into one rectangular tile of a row-major 2D grid.
+/// Tiles handed out by a single dispatch never overlap.
+pub struct TileMut<'a, A> {
+    ptr: *mut A,
+    stride: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    _marker: PhantomData<&'a mut [A]>,
+}
+
+/// Safe because every tile covers a disjoint region of the grid
+unsafe impl<'a, A: Send> Send for TileMut<'a, A> {}
+
+impl<'a, A> TileMut<'a, A> {
+    /// Caller must make sure the tile lies inside the grid and that
+    /// no other live tile covers the same region.
+    #[inline]
+    pub(crate) unsafe fn new(
+        grid: SendPtr<A>,
+        stride: usize,
+        (x, y): (usize, usize),
+        (width, height): (usize, usize),
+    ) -> Self {
+        Self {
+            ptr: grid.0.add(y * stride + x),
+            stride,
+            x,
+            y,
+            width,
+            height,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Column of the top left element in the grid
+    #[inline]
+    pub fn x(&self) -> usize {
+        self.x
+    }
+
+    /// Row of the top left element in the grid
+    #[inline]
+    pub fn y(&self) -> usize {
+        self.y
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns row `row` of the tile, relative to the top of the tile
+    #[inline]
+    pub fn row(&self, row: usize) -> &[A] {
+        assert!(row < self.height);
+        unsafe { slice::from_raw_parts(self.ptr.add(row * self.stride), self.width) }
+    }
+
+    #[inline]
+    pub fn row_mut(&mut self, row: usize) -> &mut [A] {
+        assert!(row < self.height);
+        unsafe { slice::from_raw_parts_mut(self.ptr.add(row * self.stride), self.width) }
+    }
+
+    /// Element at tile local coordinates
+    #[inline]
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut A {
+        &mut self.row_mut(y)[x]
+    }
+
+    #[inline]
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [A]> + '_ {
+        let (ptr, stride, width) = (self.ptr, self.stride, self.width);
+        (0..self.height)
+            .map(move |row| unsafe { slice::from_raw_parts_mut(ptr.add(row * stride), width) })
+    }
+}
+
+/// Mutable view into one box shaped tile of a 3D grid stored as
+/// consecutive row-major planes.
+pub struct Tile3Mut<'a, A> {
+    ptr: *mut A,
+    stride: usize,
+    plane: usize,
+    x: usize,
+    y: usize,
+    z: usize,
+    width: usize,
+    height: usize,
+    depth: usize,
+    _marker: PhantomData<&'a mut [A]>,
+}
+
+/// Safe because every tile covers a disjoint region of the grid
+unsafe impl<'a, A: Send> Send for Tile3Mut<'a, A> {}
+
+impl<'a, A> Tile3Mut<'a, A> {
+    /// Caller must make sure the tile lies inside the grid and that
+    /// no other live tile covers the same region.
+    #[inline]
+    pub(crate) unsafe fn new(
+        grid: SendPtr<A>,
+        stride: usize,
+        plane: usize,
+        (x, y, z): (usize, usize, usize),
+        (width, height, depth): (usize, usize, usize),
+    ) -> Self {
+        Self {
+            ptr: grid.0.add(z * plane + y * stride + x),
+            stride,
+            plane,
+            x,
+            y,
+            z,
+            width,
+            height,
+            depth,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn x(&self) -> usize {
+        self.x
+    }
+
+    #[inline]
+    pub fn y(&self) -> usize {
+        self.y
+    }
+
+    #[inline]
+    pub fn z(&self) -> usize {
+        self.z
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the row at tile local `row` in tile local plane `plane`
+    #[inline]
+    pub fn row(&self, row: usize, plane: usize) -> &[A] {
+        assert!(row < self.height && plane < self.depth);
+        let start = plane * self.plane + row * self.stride;
+        unsafe { slice::from_raw_parts(self.ptr.add(start), self.width) }
+    }
+
+    #[inline]
+    pub fn row_mut(&mut self, row: usize, plane: usize) -> &mut [A] {
+        assert!(row < self.height && plane < self.depth);
+        let start = plane * self.plane + row * self.stride;
+        unsafe { slice::from_raw_parts_mut(self.ptr.add(start), self.width) }
+    }
+
+    /// Element at tile local coordinates
+    #[inline]
+    pub fn get_mut(&mut self, x: usize, y: usize, z: usize) -> &mut A {
+        &mut self.row_mut(y, z)[x]
+    }
+}
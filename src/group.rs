@@ -0,0 +1,152 @@
+use crate::job::SendPtr;
+use crate::pool::Pool;
+
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+/// Smallest number of bins summed by one worker when merging histograms
+const MERGE_BINS: usize = 1024;
+
+impl Pool {
+    /// Counts how many inputs fall in each of the `bins` dense integer keys
+    /// returned by `key`. Keys outside `0..bins` are not counted.
+    #[inline]
+    pub fn histogram<A, K>(&self, inputs: &[A], key: K, bins: usize) -> Vec<usize>
+    where
+        A: Sync,
+        K: Fn(&A) -> usize + Send + Sync,
+    {
+        let partials = self.partials(inputs.len(), |indices| {
+            let mut counts = vec![0usize; bins];
+            for index in indices {
+                if let Some(count) = counts.get_mut(key(&inputs[index])) {
+                    *count += 1;
+                }
+            }
+            counts
+        });
+
+        let mut counts = vec![0usize; bins];
+        let chunk = cmp::max(bins.div_ceil(self.num_threads * 4), MERGE_BINS);
+        let ptr = SendPtr(counts.as_mut_ptr());
+        self.dispatch_fn(bins.div_ceil(chunk), |block| {
+            let start = block * chunk;
+            let end = cmp::min(start + chunk, bins);
+            for bin in start..end {
+                // Every block sums its own range of bins
+                *unsafe { ptr.get(bin) } = partials.iter().map(|counts| counts[bin]).sum();
+            }
+        });
+        counts
+    }
+
+    /// Groups the inputs by `key` and combines the `value` of every input
+    /// in a group with `combine`, which must be associative and commutative.
+    /// Every worker builds its own maps, split into shards by key hash, then
+    /// the shards are merged in parallel.
+    #[inline]
+    pub fn group_by_reduce<A, K, V, KF, VF, C>(
+        &self,
+        inputs: &[A],
+        key: KF,
+        value: VF,
+        combine: C,
+    ) -> HashMap<K, V>
+    where
+        A: Sync,
+        K: Eq + Hash + Send,
+        V: Send,
+        KF: Fn(&A) -> K + Send + Sync,
+        VF: Fn(&A) -> V + Send + Sync,
+        C: Fn(V, V) -> V + Send + Sync,
+    {
+        let shards = self.num_threads * 4;
+        let hasher = RandomState::new();
+
+        let partials = self.partials(inputs.len(), |indices| {
+            let mut maps: Vec<HashMap<K, V>> = (0..shards).map(|_| HashMap::new()).collect();
+            for index in indices {
+                let input = &inputs[index];
+                let key = key(input);
+                let shard = (hasher.hash_one(&key) % shards as u64) as usize;
+                insert_with(&mut maps[shard], key, value(input), &combine);
+            }
+            maps
+        });
+
+        // Regroup the partial maps so each shard can be merged on its own
+        let mut by_shard: Vec<Vec<HashMap<K, V>>> = (0..shards).map(|_| Vec::new()).collect();
+        for maps in partials {
+            for (shard, map) in maps.into_iter().enumerate() {
+                by_shard[shard].push(map);
+            }
+        }
+
+        let ptr = SendPtr(by_shard.as_mut_ptr());
+        self.dispatch_fn(shards, |shard| {
+            // Every shard index is handed out exactly once
+            let maps = unsafe { ptr.get(shard) };
+            let mut merged = maps.pop().unwrap_or_default();
+            for map in maps.drain(..) {
+                for (key, value) in map {
+                    insert_with(&mut merged, key, value, &combine);
+                }
+            }
+            maps.push(merged);
+        });
+
+        let mut groups = HashMap::new();
+        for mut maps in by_shard {
+            groups.extend(maps.pop().unwrap_or_default());
+        }
+        groups
+    }
+}
+
+#[inline]
+fn insert_with<K, V, C>(map: &mut HashMap<K, V>, key: K, value: V, combine: &C)
+where
+    K: Eq + Hash,
+    C: Fn(V, V) -> V,
+{
+    let value = match map.remove(&key) {
+        Some(prev) => combine(prev, value),
+        None => value,
+    };
+    map.insert(key, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let pool = Pool::new(3);
+        let array: Vec<usize> = (0..100_000).collect();
+        let counts = pool.histogram(&array, |val| val % 1500, 1000);
+        let mut expected = vec![0; 1000];
+        for val in array.iter() {
+            if val % 1500 < 1000 {
+                expected[val % 1500] += 1;
+            }
+        }
+        assert_eq!(counts, expected);
+        assert!(pool.histogram(&array, |val| *val, 0).is_empty());
+    }
+
+    #[test]
+    fn test_group_by_reduce() {
+        let pool = Pool::new(3);
+        let words: Vec<String> = (0..50_000).map(|i| format!("key{}", i % 317)).collect();
+        let groups = pool.group_by_reduce(&words, |word| word.clone(), |_| 1usize, |a, b| a + b);
+
+        let mut expected = HashMap::new();
+        for word in words.iter() {
+            *expected.entry(word.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(groups, expected);
+    }
+}
@@ -0,0 +1,297 @@
+#[cfg(target_os = "linux")]
+use crate::eventfd::EventFd;
+use crate::job::{Job, SendPtr};
+use crate::pool::Pool;
+
+#[cfg(target_os = "linux")]
+use std::io;
+use std::marker::PhantomData;
+use std::panic;
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Running dispatch which borrows its elements for `'a`.
+/// Dropping the handle waits for the dispatch to complete.
+pub struct DispatchHandle<'a> {
+    job: Arc<Job>,
+    #[cfg(target_os = "linux")]
+    event: Option<EventFd>,
+    borrow: PhantomData<&'a mut ()>,
+}
+
+impl<'a> DispatchHandle<'a> {
+    /// Fraction of the elements processed or cancelled so far, in `[0, 1]`
+    #[inline]
+    pub fn progress(&self) -> f64 {
+        self.job.progress()
+    }
+
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.job.is_complete()
+    }
+
+    /// Blocks until every element was processed or cancelled, processing
+    /// elements on this thread meanwhile. Resumes a panic of `func`.
+    #[inline]
+    pub fn wait(&self) {
+        // The workers may all be busy waiting on this thread
+        self.job.execute();
+        self.job.wait();
+        if let Some(payload) = self.job.take_panic() {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Like wait but gives up after `timeout`. Returns whether the
+    /// dispatch completed.
+    #[inline]
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.job.wait_timeout(timeout)
+    }
+
+    /// Stops workers from starting on elements which are not claimed yet.
+    /// Elements already being processed still finish.
+    #[inline]
+    pub fn cancel(&self) {
+        self.job.cancel();
+    }
+
+    /// Eventfd which becomes readable once the dispatch completed, for
+    /// event loops which can't block in wait. Created on the first call,
+    /// later calls return the same fd.
+    #[cfg(target_os = "linux")]
+    pub fn eventfd(&mut self) -> io::Result<&EventFd> {
+        if self.event.is_none() {
+            let event = EventFd::new()?;
+            let waker = event.waker();
+            // Signals right away if the dispatch already completed
+            if let Poll::Ready(()) = self.job.poll_complete(&mut Context::from_waker(&waker)) {
+                waker.wake();
+            }
+            self.event = Some(event);
+        }
+        Ok(self.event.as_ref().unwrap())
+    }
+}
+
+impl<'a> Drop for DispatchHandle<'a> {
+    fn drop(&mut self) {
+        self.job.execute();
+        self.job.wait();
+    }
+}
+
+impl Pool {
+    /// Starts dispatching `func` over `elems` and runs `body` with a handle
+    /// to the running dispatch, e.g. to poll its progress or cancel it.
+    /// Waits for the dispatch to complete before returning and resumes a
+    /// panic of `func`.
+    #[inline]
+    pub fn submit_scope<'a, F, A, B, R>(&self, elems: &'a mut [A], func: F, body: B) -> R
+    where
+        A: Send,
+        F: Fn(&mut A) + Send + Sync + 'a,
+        B: FnOnce(&mut DispatchHandle<'a>) -> R,
+    {
+        // The handle never leaves this frame, dropping it waits
+        let mut handle = unsafe { self.submit_mut(elems, func) };
+        let result = body(&mut handle);
+        handle.wait();
+        result
+    }
+
+    /// Like dispatch_mut but returns right away with a handle to the
+    /// running dispatch. Pool::submit_scope is the safe version, this is
+    /// for handles which have to be kept in a struct.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be dropped or waited on before `elems` is used
+    /// again. Leaking it, e.g. with mem::forget, ends the borrow while
+    /// workers may still write to `elems`.
+    #[inline]
+    pub unsafe fn submit_mut<'a, F, A>(&self, elems: &'a mut [A], func: F) -> DispatchHandle<'a>
+    where
+        A: Send,
+        F: Fn(&mut A) + Send + Sync + 'a,
+    {
+        let ptr = SendPtr(elems.as_mut_ptr());
+        let job = Job::from_fn(
+            elems.len(),
+            move |index| func(ptr.get(index)),
+            self.num_threads,
+        );
+        DispatchHandle {
+            job: self.submit(job),
+            #[cfg(target_os = "linux")]
+            event: None,
+            borrow: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_submit_mut() {
+        let pool = Pool::new(2);
+        let mut array = vec![0usize; 10_000];
+        {
+            let handle = unsafe { pool.submit_mut(&mut array, |val| *val += 1) };
+            handle.wait();
+            assert!(handle.is_done());
+            assert_eq!(handle.progress(), 1.0);
+            assert!(handle.wait_timeout(Duration::from_millis(1)));
+        }
+        assert!(array.iter().all(|val| *val == 1));
+
+        // Dropping the handle waits for the dispatch
+        unsafe { pool.submit_mut(&mut array, |val| *val += 1) };
+        assert!(array.iter().all(|val| *val == 2));
+    }
+
+    #[test]
+    fn test_submit_scope() {
+        let pool = Pool::new(2);
+        let mut array = vec![0usize; 10_000];
+        let progress = pool.submit_scope(
+            &mut array,
+            |val| *val += 1,
+            |handle| {
+                handle.wait();
+                assert!(handle.is_done());
+                handle.progress()
+            },
+        );
+        assert_eq!(progress, 1.0);
+        assert!(array.iter().all(|val| *val == 1));
+
+        // Returns only once the dispatch completed, even without a wait
+        pool.submit_scope(&mut array, |val| *val += 1, |_| ());
+        assert!(array.iter().all(|val| *val == 2));
+    }
+
+    #[test]
+    fn test_submit_scope_on_worker() {
+        // The only worker waits on the dispatch, so it has to run it too
+        let pool = Arc::new(Pool::new(1));
+        let inner = pool.clone();
+        let handle = pool.spawn(move || {
+            let mut array = vec![0usize; 1000];
+            inner.submit_scope(&mut array, |val| *val += 1, |handle| handle.wait());
+            inner.submit_scope(&mut array, |val| *val += 1, |_| ());
+            array.iter().sum::<usize>()
+        });
+        assert_eq!(handle.join().unwrap(), 2000);
+
+        let mut array = vec![0usize; 1000];
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.submit_scope(&mut array, |_| panic!("dispatch failed"), |_| ())
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_submit_scope_progress() {
+        let pool = Pool::new(2);
+        let release = AtomicBool::new(false);
+        let mut array: Vec<usize> = (0..2000).collect();
+        // The second half waits, so only the first half can finish
+        let wait = |val: &mut usize| {
+            while *val >= 1000 && !release.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            *val = 0;
+        };
+        let progress = pool.submit_scope(&mut array, wait, |handle| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while handle.progress() < 0.5 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let progress = handle.progress();
+            release.store(true, Ordering::Release);
+            progress
+        });
+        assert_eq!(progress, 0.5);
+        assert!(array.iter().all(|val| *val == 0));
+    }
+
+    #[test]
+    fn test_submit_mut_cancel() {
+        let pool = Pool::new(2);
+        let release = AtomicBool::new(false);
+        let mut array = vec![0usize; 10_000];
+        let wait = |val: &mut usize| {
+            while !release.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            *val = 1;
+        };
+        pool.submit_scope(&mut array, wait, |handle| {
+            assert!(!handle.wait_timeout(Duration::from_millis(10)));
+            assert!(!handle.is_done());
+            assert!(handle.progress() < 1.0);
+
+            handle.cancel();
+            release.store(true, Ordering::Release);
+            handle.wait();
+            assert!(handle.is_done());
+            assert_eq!(handle.progress(), 1.0);
+        });
+        assert!(array.iter().filter(|val| **val == 1).count() < array.len());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_submit_scope_eventfd() {
+        use std::os::unix::io::AsRawFd;
+
+        let pool = Pool::new(2);
+        let release = AtomicBool::new(false);
+        let mut array = vec![0usize; 1000];
+        let wait = |val: &mut usize| {
+            while !release.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            *val = 1;
+        };
+        pool.submit_scope(&mut array, wait, |handle| {
+            let event = handle.eventfd().unwrap();
+            assert!(event.as_raw_fd() >= 0);
+            assert_eq!(
+                event.read().unwrap_err().kind(),
+                std::io::ErrorKind::WouldBlock
+            );
+
+            release.store(true, Ordering::Release);
+            let mut poll = libc::pollfd {
+                fd: event.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            assert_eq!(unsafe { libc::poll(&mut poll, 1, 10_000) }, 1);
+            assert_eq!(event.read().unwrap(), 1);
+            assert!(handle.is_done());
+        });
+        assert!(array.iter().all(|val| *val == 1));
+
+        // Already completed dispatches signal as soon as the fd is created
+        pool.submit_scope(
+            &mut array,
+            |val| *val = 2,
+            |handle| {
+                handle.wait();
+                assert_eq!(handle.eventfd().unwrap().read().unwrap(), 1);
+            },
+        );
+    }
+}
@@ -1,20 +1,34 @@
 use crate::lockedBatch::LockedBatch;
 
 use parking_lot::{Condvar, Mutex};
+use std::any::Any;
 use std::cmp;
 use std::os::raw::c_void;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 use smallvec::SmallVec;
 
 pub struct Job {
     func: Progress,
-    ctx: *mut c_void,
-    elems: *mut c_void,
+    /// Boxed closure, taken out and dropped by the first Job::wait to
+    /// return or by Job::drop, whichever comes first
+    ctx: AtomicPtr<c_void>,
+    drop_ctx: unsafe fn(*mut c_void),
     num: usize,
     done_index: AtomicUsize,
+    /// Indices finished so far, published per batch for Job::progress.
+    /// Only done_index decides when the job is complete.
+    finished: AtomicUsize,
     waker_lock: Arc<(Mutex<bool>, Condvar)>,
+    /// Woken by the last worker to finish, for jobs awaited as futures
+    waker: Mutex<Option<Waker>>,
+    /// First panic of the closure, kept for whoever waits on the job
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
 
     locked_batch: SmallVec<[LockedBatch; 64]>,
 }
@@ -28,7 +42,113 @@ unsafe impl Send for Job {}
 /// Job::wait must be called to complete the job
 unsafe impl Sync for Job {}
 
-type Progress = extern "C" fn(*mut c_void, *mut c_void, u64);
+type Progress = unsafe fn(*mut c_void, &mut Indices);
+
+/// Hands out the indices a worker claims from the batches of a job.
+/// Every index returned counts as completed once Job::execute returns.
+pub struct Indices<'a> {
+    job: &'a Job,
+    locked_batch: Option<&'a LockedBatch>,
+    /// Whether locked_batch was claimed by this worker rather than shared
+    owner: bool,
+    first: Option<usize>,
+    completed: usize,
+    /// Part of completed already added to Job::finished
+    reported: usize,
+}
+
+impl<'a> Iterator for Indices<'a> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        if self.first.is_some() {
+            return self.first.take();
+        }
+
+        loop {
+            let locked_batch = match self.locked_batch {
+                Some(locked_batch) => locked_batch,
+                None => {
+                    let (locked_batch, owner) = self.job.get_free_slot()?;
+                    self.locked_batch = Some(locked_batch);
+                    self.owner = owner;
+                    continue;
+                }
+            };
+
+            let index = locked_batch.get_next_index();
+
+            if index >= locked_batch.count {
+                if locked_batch.is_cancelled() {
+                    self.completed += locked_batch.drain_cancelled(self.owner);
+                }
+                if !locked_batch.is_sharing() {
+                    locked_batch.force_inc_counter(index);
+                }
+                self.locked_batch = None;
+                self.report();
+                continue;
+            }
+
+            self.completed += 1;
+            return Some(index + locked_batch.offset);
+        }
+    }
+}
+
+impl<'a> Indices<'a> {
+    /// Publishes the indices finished since the last report. Every index
+    /// handed out before the current call to next has finished.
+    #[inline]
+    fn report(&mut self) {
+        let finished = self.completed - self.reported;
+        self.job.finished.fetch_add(finished, Ordering::Relaxed);
+        self.reported = self.completed;
+    }
+
+    /// Cancels the job these indices belong to
+    #[inline]
+    pub fn cancel(&self) {
+        self.job.cancel();
+    }
+
+    /// Cancels the batches of the job which start after `index`
+    #[inline]
+    pub fn cancel_after(&self, index: usize) {
+        self.job.cancel_after(index);
+    }
+}
+
+/// Raw pointer which can be moved into a job closure.
+/// Users must make sure each index is only touched by one worker.
+pub(crate) struct SendPtr<T>(pub *mut T);
+
+impl<T> Clone for SendPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SendPtr<T> {}
+
+unsafe impl<T> Send for SendPtr<T> {}
+unsafe impl<T> Sync for SendPtr<T> {}
+
+impl<T> SendPtr<T> {
+    /// Safe as long as index is in bounds and not aliased.
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get(&self, index: usize) -> &mut T {
+        &mut *self.0.add(index)
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.drop_closure();
+    }
+}
 
 impl Job {
     /// This function is unsafe because the Job object must
@@ -38,67 +158,123 @@ impl Job {
     where
         F: Fn(&mut A) + Send + Sync,
     {
-        let mut closure = |ptr: *mut c_void, index: u64| {
-            let elem: &mut A = std::mem::transmute((ptr as *mut A).add(index as usize));
-            (func)(elem)
-        };
-
-        let cores = cmp::max(cores, 1) * 8;
+        let ptr = SendPtr(elems.as_mut_ptr());
+        Job::from_fn(elems.len(), move |index| (func)(ptr.get(index)), cores)
+    }
 
-        let estim_per_batch = elems.len() / cores;
-        let batch_overflow = elems.len() % cores;
+    /// Creates a job which calls `func` once for every index in `0..num`.
+    /// Same contract as Job::new, Job::wait must be called before returning.
+    #[inline]
+    pub unsafe fn from_fn<F>(num: usize, func: F, cores: usize) -> Self
+    where
+        F: Fn(usize) + Send + Sync,
+    {
+        Job::from_runner(
+            num,
+            move |indices: &mut Indices| indices.for_each(&func),
+            cores,
+        )
+    }
 
-        let locked_batch = (0..cores)
-            .map(|i| {
-                let (num, offset) = if i == 0 {
-                    (estim_per_batch + batch_overflow, 0)
-                } else {
-                    (estim_per_batch, (i * estim_per_batch) + batch_overflow)
-                };
+    /// Like Job::from_fn but every worker gets its own state from `init`.
+    /// The state is created once per Job::execute, before the batch loop.
+    #[inline]
+    pub unsafe fn with_init<I, S, F>(num: usize, init: I, func: F, cores: usize) -> Self
+    where
+        I: Fn() -> S + Send + Sync,
+        F: Fn(&mut S, usize) + Send + Sync,
+    {
+        Job::from_runner(
+            num,
+            move |indices: &mut Indices| {
+                let mut state = init();
+                for index in indices {
+                    func(&mut state, index);
+                }
+            },
+            cores,
+        )
+    }
 
-                LockedBatch::new(num, offset)
-            })
+    /// Creates a job from a runner which must drain the indices it is handed.
+    /// The runner is called at most once per Job::execute and only once
+    /// an index has been claimed, so it never runs after the job completed.
+    #[inline]
+    pub unsafe fn from_runner<R>(num: usize, runner: R, cores: usize) -> Self
+    where
+        R: Fn(&mut Indices) + Send + Sync,
+    {
+        let locked_batch = Job::batch_ranges(num, cores)
+            .map(|(num, offset)| LockedBatch::new(num, offset))
             .collect();
 
-        let (ctx, func) = Job::unpack_closure(&mut closure);
+        let (ctx, func, drop_ctx) = Job::unpack_closure(runner);
         Self {
-            elems: elems.as_mut_ptr() as *mut c_void,
-            num: elems.len(),
+            num,
             done_index: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
             func,
-            ctx,
+            ctx: AtomicPtr::new(ctx),
+            drop_ctx,
             locked_batch,
             waker_lock: Arc::new((Mutex::new(false), Condvar::new())),
+            waker: Mutex::new(None),
+            panic: Mutex::new(None),
         }
     }
 
+    /// Splits `0..num` into the `(count, offset)` ranges of each batch,
+    /// the first batch takes the overflow.
+    #[inline]
+    pub fn batch_ranges(num: usize, cores: usize) -> impl Iterator<Item = (usize, usize)> {
+        let cores = cmp::max(cores, 1) * 8;
+
+        let estim_per_batch = num / cores;
+        let batch_overflow = num % cores;
+
+        (0..cores).map(move |i| {
+            if i == 0 {
+                (estim_per_batch + batch_overflow, 0)
+            } else {
+                (estim_per_batch, (i * estim_per_batch) + batch_overflow)
+            }
+        })
+    }
+
     /// This function unpacks the closure into a context and a trampoline
+    /// The closure is boxed so it lives as long as the job does
     /// Source: https://s3.amazonaws.com/temp.michaelfbryan.com/callbacks/index.html
     #[inline]
-    unsafe fn unpack_closure<F>(closure: &mut F) -> (*mut c_void, Progress)
+    unsafe fn unpack_closure<R>(closure: R) -> (*mut c_void, Progress, unsafe fn(*mut c_void))
     where
-        F: FnMut(*mut c_void, u64),
+        R: Fn(&mut Indices),
     {
-        extern "C" fn trampoline<F>(data: *mut c_void, elems: *mut c_void, n: u64)
+        unsafe fn trampoline<R>(data: *mut c_void, indices: &mut Indices)
         where
-            F: FnMut(*mut c_void, u64),
+            R: Fn(&mut Indices),
         {
-            let closure: &mut F = unsafe { &mut *(data as *mut F) };
-            (*closure)(elems, n);
+            let closure: &R = &*(data as *const R);
+            (*closure)(indices);
+        }
+
+        unsafe fn drop_closure<R>(data: *mut c_void) {
+            drop(Box::from_raw(data as *mut R));
         }
 
-        (closure as *mut F as *mut c_void, trampoline::<F>)
+        let closure = Box::into_raw(Box::new(closure));
+        (closure as *mut c_void, trampoline::<R>, drop_closure::<R>)
     }
 
+    /// Returns a batch to work on and whether the caller owns it
     #[inline]
-    fn get_free_slot(&self) -> Option<&LockedBatch> {
+    fn get_free_slot(&self) -> Option<(&LockedBatch, bool)> {
         // First try to find a free slot
         for slot in self.locked_batch.iter() {
             if slot.is_free() {
                 if !slot.own() {
                     continue;
                 }
-                return Some(slot);
+                return Some((slot, true));
             }
         }
 
@@ -110,39 +286,82 @@ impl Job {
 
         if let Some(slot) = found {
             slot.add_worker();
-            return Some(slot);
+            return Some((slot, false));
         }
         None
     }
 
+    /// Runs indices of the job until none are left to claim. Never unwinds,
+    /// a panic of the closure cancels the job and is kept for Job::take_panic.
     #[inline]
     pub fn execute(&self) {
-        let mut completed = 0;
+        let mut indices = Indices {
+            job: self,
+            locked_batch: None,
+            owner: false,
+            first: None,
+            completed: 0,
+            reported: 0,
+        };
 
-        loop {
-            let free_slot = self.get_free_slot();
-            if free_slot.is_none() {
-                break;
+        // Don't touch the closure unless we own an index, once every
+        // index is claimed the caller may already have returned
+        indices.first = indices.next();
+        if indices.first.is_some() {
+            let ctx = self.ctx.load(Ordering::Acquire);
+            let run = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+                (self.func)(ctx, &mut indices)
+            }));
+            if let Err(payload) = run {
+                // Nobody runs the rest of our batch now, skip it and every
+                // unclaimed index so Job::wait still returns
+                self.cancel();
+                while indices.next().is_some() {}
+                self.panic.lock().get_or_insert(payload);
             }
+        }
 
-            let locked_batch = free_slot.unwrap();
-            loop {
-                let index = locked_batch.get_next_index();
+        indices.report();
+        self.complete(indices.completed);
+    }
 
-                if index >= locked_batch.count {
-                    if !locked_batch.is_sharing() {
-                        locked_batch.force_inc_counter(index);
-                    }
-                    break;
-                }
+    /// Takes the panic of the closure, if it panicked. The job was
+    /// cancelled at that point, so only some of the indices ran.
+    #[inline]
+    pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.panic.lock().take()
+    }
 
-                (self.func)(self.ctx, self.elems, (index + locked_batch.offset) as u64);
-                completed += 1;
-            }
+    /// Stops workers claiming new indices. Indices already claimed still
+    /// finish before Job::wait returns.
+    #[inline]
+    pub fn cancel(&self) {
+        let skipped = self.locked_batch.iter().map(|slot| slot.cancel()).sum();
+        self.skip(skipped);
+    }
 
-            // println!("Finished work with pool: {}", locked_batch.offset);
-        }
-        // println!("Thread is closing... we did {}", completed);
+    /// Like Job::cancel but only for batches with an offset above `index`,
+    /// batches at or below it still run to completion.
+    #[inline]
+    pub fn cancel_after(&self, index: usize) {
+        let skipped = self
+            .locked_batch
+            .iter()
+            .filter(|slot| slot.offset > index)
+            .map(|slot| slot.cancel())
+            .sum();
+        self.skip(skipped);
+    }
+
+    /// Completes indices nobody claimed
+    #[inline]
+    fn skip(&self, skipped: usize) {
+        self.finished.fetch_add(skipped, Ordering::Relaxed);
+        self.complete(skipped);
+    }
+
+    #[inline]
+    fn complete(&self, completed: usize) {
         let done = self.done_index.fetch_add(completed, Ordering::Release);
 
         if done + completed >= self.num {
@@ -150,9 +369,72 @@ impl Job {
             let mut started = lock.lock();
             *started = true;
             cvar.notify_all();
+            drop(started);
+
+            if let Some(waker) = self.waker.lock().take() {
+                waker.wake();
+            }
         }
     }
 
+    /// Fraction of the indices completed or cancelled so far, in `[0, 1]`
+    #[inline]
+    pub fn progress(&self) -> f64 {
+        if self.num == 0 {
+            return 1.0;
+        }
+        let finished = cmp::min(self.finished.load(Ordering::Relaxed), self.num);
+        finished as f64 / self.num as f64
+    }
+
+    /// True once Job::wait would return without blocking
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        *self.waker_lock.0.lock()
+    }
+
+    /// Non-blocking Job::wait for futures, `cx` is woken once the job completed
+    #[inline]
+    pub fn poll_complete(&self, cx: &mut Context) -> Poll<()> {
+        if self.is_complete() {
+            return Poll::Ready(());
+        }
+        {
+            let mut waker = self.waker.lock();
+            match *waker {
+                Some(ref waker) if waker.will_wake(cx.waker()) => {}
+                _ => *waker = Some(cx.waker().clone()),
+            }
+        }
+        // The job may have completed before the waker was stored
+        if self.is_complete() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    /// Like Job::wait but gives up after `timeout`. Returns whether the
+    /// job completed.
+    #[inline]
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.waker_lock;
+
+        let mut started = lock.lock();
+        while !*started {
+            if cvar.wait_until(&mut started, deadline).timed_out() {
+                break;
+            }
+        }
+        let complete = *started;
+        drop(started);
+
+        if complete {
+            self.drop_closure();
+        }
+        complete
+    }
+
     #[inline]
     pub fn wait(&self) {
         let &(ref lock, ref cvar) = &*(self.waker_lock.clone());
@@ -161,5 +443,20 @@ impl Job {
         if !*started {
             cvar.wait(&mut started);
         }
+        drop(started);
+
+        self.drop_closure();
+    }
+
+    /// Drops the closure once the job completed. The closure may borrow
+    /// from the frame waiting on the job, so it must not be left for
+    /// whichever worker happens to release the last Arc<Job>.
+    #[inline]
+    fn drop_closure(&self) {
+        let ctx = self.ctx.swap(ptr::null_mut(), Ordering::AcqRel);
+        if !ctx.is_null() {
+            // Complete, so no worker calls the closure anymore
+            unsafe { (self.drop_ctx)(ctx) }
+        }
     }
 }
@@ -1,5 +1,23 @@
+pub mod broadcast;
+pub mod grid;
+pub mod handle;
+#[cfg(target_os = "linux")]
+pub mod eventfd;
+mod filter;
+pub mod future;
+mod group;
 mod job;
 mod lockedBatch;
 pub mod newJob;
 
 pub mod pool;
+mod reduce;
+pub mod rng;
+mod scan;
+mod search;
+mod sort;
+pub mod stencil;
+mod strand;
+pub mod task;
+pub mod timer;
+pub mod worklist;
@@ -1,4 +1,5 @@
 use parking_lot::{Condvar, Mutex};
+use std::cmp;
 use std::sync::Arc;
 use std::{
     cell::UnsafeCell,
@@ -8,7 +9,7 @@ use std::{
 const FREE: u8 = 0b0000_0000;
 const OWNED: u8 = 0b0000_0001;
 
-const COMPLETE: u8 = 0b000_0010;
+const CANCELLED: u8 = 0b0000_0010;
 
 const PENDINGSHARE: u8 = 0b0000_0100;
 const SHARING: u8 = 0b0000_1000;
@@ -112,8 +113,13 @@ impl LockedBatch {
 
     #[inline]
     pub fn get_next_index(&self) -> usize {
-        let is_sharing = self.is_sharing();
-        if !is_sharing && self.is_wanting_share() {
+        let state = self.state.load(Ordering::Acquire);
+        if (state & CANCELLED) != 0 {
+            return self.count;
+        }
+
+        let is_sharing = (state & SHARING) != 0;
+        if !is_sharing && (state & PENDINGSHARE) != 0 {
             // println!("Pool offset {} is now sharing", self.offset);
             self.cntr
                 .fetch_add(unsafe { *(self.fast_cntr.get()) }, Ordering::Release);
@@ -176,6 +182,45 @@ impl LockedBatch {
 
     #[inline]
     pub fn is_complete(&self) -> bool {
-        self.cntr.load(Ordering::Relaxed) >= self.count
+        self.cntr.load(Ordering::Relaxed) >= self.count || self.is_cancelled()
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        (self.state.load(Ordering::Acquire) & CANCELLED) != 0
+    }
+
+    /// Stops any more indices being handed out. Returns how many indices the
+    /// caller skipped, which is the whole batch if nobody owned it yet.
+    /// Otherwise the workers in the batch pick up the rest via drain_cancelled.
+    #[inline]
+    pub fn cancel(&self) -> usize {
+        let prev = self.state.fetch_or(CANCELLED, Ordering::AcqRel);
+        if prev == FREE {
+            self.count
+        } else {
+            0
+        }
+    }
+
+    /// Claims every index which was not handed out before the batch was
+    /// cancelled and returns how many there were. Only one caller will ever
+    /// get a non zero count. Until the batch shares only its `owner` has
+    /// handed out indices, so the other workers get nothing.
+    #[inline]
+    pub fn drain_cancelled(&self, owner: bool) -> usize {
+        if self.is_sharing() {
+            let index = self.cntr.fetch_add(self.count, Ordering::AcqRel);
+            self.count.saturating_sub(index)
+        } else if owner {
+            // Only the owner touches the fast counter until it starts sharing
+            unsafe {
+                let index = *self.fast_cntr.get();
+                *self.fast_cntr.get() = cmp::max(index, self.count);
+                self.count.saturating_sub(index)
+            }
+        } else {
+            0
+        }
     }
 }
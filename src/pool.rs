@@ -1,43 +1,110 @@
-use crate::job::Job;
+use crate::grid::{Tile3Mut, TileMut};
+use crate::job::{Indices, Job, SendPtr};
+use crate::strand::Strands;
+use crate::timer::Timer;
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use parking_lot::Mutex;
+use std::cell::Cell;
+use std::cmp;
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::spawn;
 use std::{sync::Arc, thread::Builder};
 
-type SendRef = Sender<Arc<Job>>;
+pub(crate) type SendRef = Sender<Message>;
+
+/// Boxed closure run once by a single worker
+pub(crate) type Task = Box<dyn FnOnce() + Send>;
+
+/// Work sent to a worker thread
+pub(crate) enum Message {
+    /// Data parallel job shared by every worker
+    Job(Arc<Job>),
+    /// Closure for this worker only
+    Task(Task),
+}
+
+/// Worker stacks run user closures, and every dispatch from inside one
+/// builds a Job with its batches inline, a few KiB each. The 10_000 bytes
+/// used before overflowed on a single nested map in debug builds. Still
+/// far below the 2 MiB std gives a spawned thread by default.
+const WORKER_STACK_SIZE: usize = 256 * 1024;
+
+/// Tells pools apart in WORKER
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Pool id and worker index of the worker running on this thread
+    static WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// Index of the worker of pool `pool_id` running on the calling thread, if any
+pub(crate) fn current_worker(pool_id: usize) -> Option<usize> {
+    match WORKER.with(Cell::get) {
+        Some((id, index)) if id == pool_id => Some(index),
+        _ => None,
+    }
+}
 
 #[derive(Debug)]
 pub struct Pool {
+    pub(crate) id: usize,
     senders: Mutex<Vec<SendRef>>,
-    num_threads: usize,
+    pub(crate) num_threads: usize,
+    next_worker: AtomicUsize,
+    /// Started by the first scheduled task
+    timer: Mutex<Option<Arc<Timer>>>,
+    pub(crate) strands: Arc<Strands>,
 }
 
 impl Default for Pool {
     fn default() -> Self {
-        let num_threads = Pool::get_thread_count();
+        Pool::new(Pool::get_thread_count())
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.get_mut().take() {
+            timer.shutdown();
+        }
+    }
+}
+
+impl Pool {
+    /// Creates a pool with `num_threads` workers
+    pub fn new(num_threads: usize) -> Self {
+        let num_threads = cmp::max(num_threads, 1);
+        let id = NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed);
         let mut senders = Vec::with_capacity(num_threads);
         (0..num_threads).for_each(|i| {
-            let (sender, recvr): (Sender<Arc<Job>>, Receiver<Arc<Job>>) = unbounded();
+            let (sender, recvr): (Sender<Message>, Receiver<Message>) = unbounded();
             let builder = Builder::new()
                 .name(format!("Worker thread {}", i))
-                .stack_size(10_000);
+                .stack_size(WORKER_STACK_SIZE);
             let _ = builder.spawn(move || {
-                for job in recvr.iter() {
-                    job.execute()
+                WORKER.with(|worker| worker.set(Some((id, i))));
+                for message in recvr.iter() {
+                    match message {
+                        Message::Job(job) => job.execute(),
+                        Message::Task(task) => task(),
+                    }
                 }
             });
             senders.push(sender);
         });
         let senders = senders;
         Self {
+            id,
             senders: Mutex::new(senders),
             num_threads,
+            next_worker: AtomicUsize::new(0),
+            timer: Mutex::new(None),
+            strands: Arc::new(Strands::new()),
         }
     }
-}
 
-impl Pool {
     pub fn get_thread_count() -> usize {
         num_cpus::get() as usize
     }
@@ -48,10 +115,29 @@ impl Pool {
         F: Fn(&mut A) + Send + Sync,
     {
         // Job must wait to completion before this frame returns
-        let job = unsafe { Job::new(elems, func, self.num_threads) };
-        let job = Arc::new(job);
-        self.notify_all(job.clone());
-        job.wait();
+        self.run(unsafe { Job::new(elems, func, self.num_threads) });
+    }
+
+    /// Like dispatch_mut but every worker gets scratch state from `init`.
+    /// The state is created at most once per worker for each dispatch,
+    /// plus once for the calling thread which helps out.
+    #[inline]
+    pub fn dispatch_mut_with<I, S, F, A>(&self, elems: &mut [A], init: I, func: F)
+    where
+        A: Send,
+        I: Fn() -> S + Send + Sync,
+        F: Fn(&mut S, &mut A) + Send + Sync,
+    {
+        let ptr = SendPtr(elems.as_mut_ptr());
+        let job = unsafe {
+            Job::with_init(
+                elems.len(),
+                init,
+                move |state: &mut S, index| func(state, ptr.get(index)),
+                self.num_threads,
+            )
+        };
+        self.run(job);
     }
 
     #[inline]
@@ -69,19 +155,265 @@ impl Pool {
         outs
     }
 
+    /// Like dispatch_mut but stops as soon as `func` returns an error.
+    /// Elements which were already claimed still finish, the rest are
+    /// skipped and the first error is returned.
+    #[inline]
+    pub fn try_dispatch_mut<F, A, E>(&self, elems: &mut [A], func: F) -> Result<(), E>
+    where
+        A: Send,
+        E: Send,
+        F: Fn(&mut A) -> Result<(), E> + Send + Sync,
+    {
+        let first_error = Mutex::new(None);
+        let ptr = SendPtr(elems.as_mut_ptr());
+        let runner = |indices: &mut Indices| {
+            while let Some(index) = indices.next() {
+                if let Err(err) = func(unsafe { ptr.get(index) }) {
+                    first_error.lock().get_or_insert(err);
+                    indices.cancel();
+                }
+            }
+        };
+        self.run(unsafe { Job::from_runner(elems.len(), runner, self.num_threads) });
+
+        match first_error.into_inner() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Like map but stops as soon as `func` returns an error.
+    #[inline]
+    pub fn try_map<F, A, B, E>(&self, inputs: &[A], func: F) -> Result<Vec<B>, E>
+    where
+        A: Sync,
+        B: Default + Clone + Send,
+        E: Send,
+        F: (Fn(&A) -> Result<B, E>) + Send + Sync,
+    {
+        let mut outs = Vec::new();
+        outs.resize(inputs.len(), B::default());
+        let mut elems: Vec<(&A, &mut B)> = inputs.iter().zip(outs.iter_mut()).collect();
+        self.try_dispatch_mut(&mut elems, move |item: &mut (&A, &mut B)| {
+            *item.1 = func(item.0)?;
+            Ok(())
+        })?;
+        Ok(outs)
+    }
+
+    /// Like map but every worker gets scratch state from `init`.
+    #[inline]
+    pub fn map_with<I, S, F, A, B>(&self, inputs: &[A], init: I, func: F) -> Vec<B>
+    where
+        A: Sync,
+        B: Default + Clone + Send,
+        I: Fn() -> S + Send + Sync,
+        F: (Fn(&mut S, &A) -> B) + Send + Sync,
+    {
+        let mut outs = Vec::new();
+        outs.resize(inputs.len(), B::default());
+        let mut elems: Vec<(&A, &mut B)> = inputs.iter().zip(outs.iter_mut()).collect();
+        self.dispatch_mut_with(&mut elems, init, move |state, item: &mut (&A, &mut B)| {
+            *item.1 = func(state, item.0);
+        });
+        outs
+    }
+
+    /// Splits a row-major `width` x `height` grid into tiles of at most
+    /// `tile_w` x `tile_h` and runs `func` once per tile. Rows start
+    /// `stride` elements apart, the padding past `width` is never touched.
+    /// Tiles rather than elements are shared between workers so each one
+    /// keeps cache locality.
+    #[inline]
+    pub fn dispatch_grid_mut<F, A>(
+        &self,
+        elems: &mut [A],
+        (width, height): (usize, usize),
+        stride: usize,
+        (tile_w, tile_h): (usize, usize),
+        func: F,
+    ) where
+        A: Send,
+        F: Fn(TileMut<A>) + Send + Sync,
+    {
+        assert!(stride >= width);
+        assert!(height == 0 || elems.len() >= (height - 1) * stride + width);
+        assert!(tile_w > 0 && tile_h > 0);
+
+        let tiles_x = width.div_ceil(tile_w);
+        let tiles_y = height.div_ceil(tile_h);
+        let grid = SendPtr(elems.as_mut_ptr());
+
+        self.dispatch_fn(tiles_x * tiles_y, move |index| {
+            let x = (index % tiles_x) * tile_w;
+            let y = (index / tiles_x) * tile_h;
+            let size = (cmp::min(tile_w, width - x), cmp::min(tile_h, height - y));
+            // Tiles never overlap and the job completes before elems is released
+            func(unsafe { TileMut::new(grid, stride, (x, y), size) })
+        });
+    }
+
+    /// 3D version of dispatch_grid_mut, the grid is stored as `depth`
+    /// consecutive row-major planes of `width` x `height`.
+    #[inline]
+    pub fn dispatch_grid3_mut<F, A>(
+        &self,
+        elems: &mut [A],
+        (width, height, depth): (usize, usize, usize),
+        (tile_w, tile_h, tile_d): (usize, usize, usize),
+        func: F,
+    ) where
+        A: Send,
+        F: Fn(Tile3Mut<A>) + Send + Sync,
+    {
+        assert_eq!(elems.len(), width * height * depth);
+        assert!(tile_w > 0 && tile_h > 0 && tile_d > 0);
+
+        let tiles_x = width.div_ceil(tile_w);
+        let tiles_y = height.div_ceil(tile_h);
+        let tiles_z = depth.div_ceil(tile_d);
+        let grid = SendPtr(elems.as_mut_ptr());
+
+        self.dispatch_fn(tiles_x * tiles_y * tiles_z, move |index| {
+            let x = (index % tiles_x) * tile_w;
+            let y = ((index / tiles_x) % tiles_y) * tile_h;
+            let z = (index / (tiles_x * tiles_y)) * tile_d;
+            let size = (
+                cmp::min(tile_w, width - x),
+                cmp::min(tile_h, height - y),
+                cmp::min(tile_d, depth - z),
+            );
+            // Tiles never overlap and the job completes before elems is released
+            func(unsafe { Tile3Mut::new(grid, width, width * height, (x, y, z), size) })
+        });
+    }
+
+    /// Runs `func` on every element of every shard. The shards are treated as
+    /// one concatenated slice so the whole set is balanced in a single job.
+    #[inline]
+    pub fn dispatch_ragged_mut<F, A>(&self, shards: &mut [&mut [A]], func: F)
+    where
+        A: Send,
+        F: Fn(&mut A) + Send + Sync,
+    {
+        let mut starts = Vec::with_capacity(shards.len());
+        let mut ptrs = Vec::with_capacity(shards.len());
+        let mut total = 0;
+        for shard in shards.iter_mut() {
+            starts.push(total);
+            ptrs.push(SendPtr(shard.as_mut_ptr()));
+            total += shard.len();
+        }
+
+        self.dispatch_fn(total, move |index| {
+            // Empty shards share their start with the next shard so take the last match
+            let shard = starts.partition_point(|&start| start <= index) - 1;
+            func(unsafe { ptrs[shard].get(index - starts[shard]) })
+        });
+    }
+
+    /// Runs `func` only on the elements at `indices`. The indices must be
+    /// sorted and unique, which also makes them safe to share between workers.
+    #[inline]
+    pub fn dispatch_indices_mut<F, A>(&self, elems: &mut [A], indices: &[usize], func: F)
+    where
+        A: Send,
+        F: Fn(&mut A) + Send + Sync,
+    {
+        assert!(
+            indices.windows(2).all(|pair| pair[0] < pair[1]),
+            "indices must be sorted and unique"
+        );
+        if let Some(&last) = indices.last() {
+            assert!(last < elems.len(), "index {} out of bounds", last);
+        }
+
+        let ptr = SendPtr(elems.as_mut_ptr());
+        self.dispatch_fn(indices.len(), move |index| {
+            func(unsafe { ptr.get(indices[index]) })
+        });
+    }
+
+    /// Runs `func` for every index in `0..num` and waits for it to finish
+    #[inline]
+    pub(crate) fn dispatch_fn<F>(&self, num: usize, func: F)
+    where
+        F: Fn(usize) + Send + Sync,
+    {
+        self.run(unsafe { Job::from_fn(num, func, self.num_threads) });
+    }
+
+    /// Sends the job to every worker and helps out until it completed.
+    /// A panic in the job is resumed here once no worker runs it anymore.
+    #[inline]
+    pub(crate) fn run(&self, job: Job) {
+        let job = self.submit(job);
+        // Take indices rather than just block, a dispatch from inside a
+        // worker may otherwise wait on workers that are all waiting too
+        job.execute();
+        // Job must wait to completion before this frame returns
+        job.wait();
+        if let Some(payload) = job.take_panic() {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Sends the job to every worker without waiting for it
+    #[inline]
+    pub(crate) fn submit(&self, job: Job) -> Arc<Job> {
+        let job = Arc::new(job);
+        self.notify_all(job.clone());
+        job
+    }
+
     #[inline]
     fn notify_all(&self, job: Arc<Job>) {
         let senders = self.senders.lock();
         for s in senders.iter() {
-            s.send(job.clone()).unwrap();
+            s.send(Message::Job(job.clone())).unwrap();
         }
     }
+
+    /// Sends the task to one worker, taking turns between the workers
+    #[inline]
+    pub(crate) fn send_task(&self, task: Task) {
+        self.next_sender().send(Message::Task(task)).unwrap();
+    }
+
+    /// Sends the task to the worker with index `worker`
+    #[inline]
+    pub(crate) fn send_task_to(&self, worker: usize, task: Task) {
+        self.senders.lock()[worker]
+            .send(Message::Task(task))
+            .unwrap();
+    }
+
+    /// Timer thread of this pool, started on first use
+    #[inline]
+    pub(crate) fn timer(&self) -> Arc<Timer> {
+        self.timer
+            .lock()
+            .get_or_insert_with(|| Timer::start(self.senders.lock().clone()))
+            .clone()
+    }
+
+    /// Channel of the worker whose turn it is to get the next task
+    #[inline]
+    pub(crate) fn next_sender(&self) -> SendRef {
+        let worker = self.next_worker.fetch_add(1, Ordering::Relaxed) % self.num_threads;
+        self.senders.lock()[worker].clone()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::cell::RefCell;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test_pool() {
@@ -94,6 +426,28 @@ mod tests {
         }
     }
     #[test]
+    fn test_closure_dropped_before_return() {
+        struct Guard<'a>(&'a AtomicUsize);
+
+        impl<'a> Drop for Guard<'a> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let pool = Pool::default();
+        for _ in 0..200 {
+            let drops = AtomicUsize::new(0);
+            let guard = Guard(&drops);
+            let mut array = [0usize; 100];
+            pool.dispatch_mut(&mut array, move |val: &mut usize| {
+                let _ = &guard;
+                *val += 1;
+            });
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+        }
+    }
+    #[test]
     fn test_map() {
         let pool = Pool::default();
         let array = [0usize; 100];
@@ -121,6 +475,59 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_nested_dispatch() {
+        // The only worker waits on the inner dispatch, the caller runs it
+        let pool = Pool::new(1);
+        let mut array = [0usize; 8];
+        pool.dispatch_mut(&mut array, |val: &mut usize| {
+            *val = pool.map(&[1usize; 100], |one| one * 2).into_iter().sum();
+        });
+        assert!(array.iter().all(|&val| val == 200));
+    }
+
+    #[test]
+    fn test_dispatch_panic() {
+        let pool = Pool::new(1);
+        let caller = thread::current().id();
+        let caller_panicked = AtomicBool::new(false);
+        let writes = AtomicUsize::new(0);
+        let mut array = vec![0usize; 10_000];
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.dispatch_mut(&mut array, |val: &mut usize| {
+                if thread::current().id() == caller {
+                    caller_panicked.store(true, Ordering::SeqCst);
+                    panic!("dispatch failed");
+                }
+                // Keep the worker busy until the caller panicked
+                while !caller_panicked.load(Ordering::SeqCst) {
+                    thread::yield_now();
+                }
+                writes.fetch_add(1, Ordering::SeqCst);
+                *val = 1;
+            })
+        }));
+        assert!(result.is_err());
+
+        // Nothing is written once the panic reached the caller
+        let written = writes.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(writes.load(Ordering::SeqCst), written);
+        assert!(written < array.len());
+
+        // The worker survives a panic too
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.dispatch_fn(1000, |index| {
+                if index == 500 {
+                    panic!("dispatch failed");
+                }
+            })
+        }));
+        assert!(result.is_err());
+        pool.dispatch_mut(&mut array, |val: &mut usize| *val = 2);
+        assert!(array.iter().all(|&val| val == 2));
+    }
+
     #[test]
     fn test_job_order_1() {
         let pool = Pool::default();
@@ -162,4 +569,171 @@ mod tests {
         job.wait();
         pool.notify_all(job.clone());
     }
+
+    #[test]
+    fn test_dispatch_with() {
+        let pool = Pool::default();
+        let inits = AtomicUsize::new(0);
+        let mut array = [0usize; 1000];
+        pool.dispatch_mut_with(
+            &mut array,
+            || {
+                inits.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(4)
+            },
+            |scratch: &mut Vec<usize>, val: &mut usize| {
+                scratch.push(1);
+                *val += scratch.pop().unwrap();
+            },
+        );
+        assert!(array.iter().all(|&val| val == 1));
+        assert!(inits.load(Ordering::Relaxed) <= pool.num_threads + 1);
+    }
+
+    #[test]
+    fn test_map_with() {
+        let pool = Pool::default();
+        let array: Vec<usize> = (0..1000).collect();
+        let output = pool.map_with(
+            &array,
+            || 0usize,
+            |calls, val: &usize| {
+                *calls += 1;
+                val * 2
+            },
+        );
+        for (i, val) in output.iter().enumerate() {
+            assert_eq!(*val, i * 2);
+        }
+    }
+
+    #[test]
+    fn test_try_dispatch() {
+        let pool = Pool::default();
+        let mut array = [0usize; 1000];
+        let ok: Result<(), usize> = pool.try_dispatch_mut(&mut array, |val: &mut usize| {
+            *val += 1;
+            Ok(())
+        });
+        assert_eq!(ok, Ok(()));
+        assert!(array.iter().all(|&val| val == 1));
+
+        let mut array: Vec<usize> = (0..100_000).collect();
+        let calls = AtomicUsize::new(0);
+        let err = pool.try_dispatch_mut(&mut array, |val: &mut usize| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            if *val % 10 == 7 {
+                return Err(*val);
+            }
+            Ok(())
+        });
+        assert_eq!(err.unwrap_err() % 10, 7);
+        assert!(calls.load(Ordering::Relaxed) < array.len());
+    }
+
+    #[test]
+    fn test_try_map() {
+        let pool = Pool::default();
+        let array: Vec<usize> = (0..1000).collect();
+        let output: Result<Vec<usize>, ()> = pool.try_map(&array, |val: &usize| Ok(val + 1));
+        assert_eq!(output.unwrap(), (1..1001).collect::<Vec<_>>());
+
+        let output = pool.try_map(
+            &array,
+            |val: &usize| if *val == 500 { Err("bad") } else { Ok(*val) },
+        );
+        assert_eq!(output, Err("bad"));
+    }
+
+    #[test]
+    fn test_dispatch_grid() {
+        let pool = Pool::default();
+        let (width, height) = (37, 23);
+        let mut grid = vec![(0usize, 0usize); width * height];
+        pool.dispatch_grid_mut(&mut grid, (width, height), width, (8, 5), |mut tile| {
+            let (x, y) = (tile.x(), tile.y());
+            for (row, elems) in tile.rows_mut().enumerate() {
+                for (col, elem) in elems.iter_mut().enumerate() {
+                    *elem = (elem.0 + 1, (y + row) * width + x + col);
+                }
+            }
+        });
+        for (i, elem) in grid.iter().enumerate() {
+            assert_eq!(*elem, (1, i));
+        }
+    }
+
+    #[test]
+    fn test_dispatch_grid_stride() {
+        let pool = Pool::default();
+        let (width, height, stride) = (19, 11, 24);
+        // The last row doesn't need its padding
+        let mut grid = vec![0usize; (height - 1) * stride + width];
+        pool.dispatch_grid_mut(&mut grid, (width, height), stride, (4, 4), |mut tile| {
+            let (x, y) = (tile.x(), tile.y());
+            for (row, elems) in tile.rows_mut().enumerate() {
+                for (col, elem) in elems.iter_mut().enumerate() {
+                    *elem += (y + row) * width + x + col + 1;
+                }
+            }
+        });
+        for (i, elem) in grid.iter().enumerate() {
+            let (x, y) = (i % stride, i / stride);
+            let expected = if x < width { y * width + x + 1 } else { 0 };
+            assert_eq!(*elem, expected);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_grid3() {
+        let pool = Pool::default();
+        let (width, height, depth) = (9, 7, 5);
+        let mut grid = vec![0usize; width * height * depth];
+        pool.dispatch_grid3_mut(&mut grid, (width, height, depth), (4, 3, 2), |mut tile| {
+            for z in 0..tile.depth() {
+                for y in 0..tile.height() {
+                    for x in 0..tile.width() {
+                        let index = ((tile.z() + z) * height + tile.y() + y) * width + tile.x() + x;
+                        *tile.get_mut(x, y, z) += index + 1;
+                    }
+                }
+            }
+        });
+        for (i, elem) in grid.iter().enumerate() {
+            assert_eq!(*elem, i + 1);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_ragged() {
+        let pool = Pool::default();
+        let mut shards: Vec<Vec<usize>> = [0, 1000, 3, 0, 0, 17, 1, 250]
+            .iter()
+            .map(|&len| vec![0; len])
+            .collect();
+        let mut slices: Vec<&mut [usize]> = shards.iter_mut().map(|s| s.as_mut_slice()).collect();
+        pool.dispatch_ragged_mut(&mut slices, |val: &mut usize| *val += 1);
+        for shard in shards.iter() {
+            assert!(shard.iter().all(|&val| val == 1));
+        }
+    }
+
+    #[test]
+    fn test_dispatch_indices() {
+        let pool = Pool::default();
+        let mut array = [0usize; 1000];
+        let indices: Vec<usize> = (0..1000).filter(|i| i % 7 == 3).collect();
+        pool.dispatch_indices_mut(&mut array, &indices, |val: &mut usize| *val += 1);
+        for (i, val) in array.iter().enumerate() {
+            assert_eq!(*val, (i % 7 == 3) as usize);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "sorted and unique")]
+    fn test_dispatch_indices_duplicate() {
+        let pool = Pool::default();
+        let mut array = [0usize; 10];
+        pool.dispatch_indices_mut(&mut array, &[1, 4, 4], |val: &mut usize| *val += 1);
+    }
 }
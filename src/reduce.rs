@@ -0,0 +1,243 @@
+use crate::job::{Indices, Job, SendPtr};
+use crate::pool::Pool;
+
+use parking_lot::Mutex;
+use std::cmp;
+use std::iter::Sum;
+
+/// Number of elements in each leaf of a deterministic reduction.
+/// Fixed so the reduction tree never depends on the number of threads.
+const DETERMINISTIC_LEAF: usize = 1024;
+
+impl Pool {
+    /// Maps every input with `map` and combines the results with `combine`.
+    /// Each worker keeps its own partial result, the partials are merged once
+    /// the job is done. Workers see indices in any order so `combine` must be
+    /// associative and commutative.
+    #[inline]
+    pub fn reduce<A, T, ID, M, C>(&self, inputs: &[A], identity: ID, map: M, combine: C) -> T
+    where
+        A: Sync,
+        T: Send,
+        ID: Fn() -> T + Send + Sync,
+        M: Fn(&A) -> T + Send + Sync,
+        C: Fn(T, T) -> T + Send + Sync,
+    {
+        self.fold(
+            inputs,
+            &identity,
+            |acc, elem| combine(acc, map(elem)),
+            &combine,
+        )
+    }
+
+    /// Like reduce but gives bit-identical results whatever the thread count,
+    /// so `combine` only needs to be associative, e.g. floating point sums.
+    /// The inputs are split into fixed size leaves which are reduced in
+    /// order, the leaf results are then combined pairwise in a fixed tree.
+    #[inline]
+    pub fn reduce_deterministic<A, T, ID, M, C>(
+        &self,
+        inputs: &[A],
+        identity: ID,
+        map: M,
+        combine: C,
+    ) -> T
+    where
+        A: Sync,
+        T: Send,
+        ID: Fn() -> T + Send + Sync,
+        M: Fn(&A) -> T + Send + Sync,
+        C: Fn(T, T) -> T + Send + Sync,
+    {
+        let leaves = inputs.len().div_ceil(DETERMINISTIC_LEAF);
+        let mut results: Vec<Option<T>> = Vec::with_capacity(leaves);
+        results.resize_with(leaves, || None);
+
+        let ptr = SendPtr(results.as_mut_ptr());
+        self.dispatch_fn(leaves, |leaf| {
+            let start = leaf * DETERMINISTIC_LEAF;
+            let end = cmp::min(start + DETERMINISTIC_LEAF, inputs.len());
+            let result = inputs[start..end]
+                .iter()
+                .fold(identity(), |acc, elem| combine(acc, map(elem)));
+            // Every leaf index is handed out exactly once
+            *unsafe { ptr.get(leaf) } = Some(result);
+        });
+
+        let mut level: Vec<T> = results.into_iter().flatten().collect();
+        while level.len() > 1 {
+            let mut next = Vec::with_capacity(level.len().div_ceil(2));
+            let mut pairs = level.into_iter();
+            while let Some(left) = pairs.next() {
+                next.push(match pairs.next() {
+                    Some(right) => combine(left, right),
+                    None => left,
+                });
+            }
+            level = next;
+        }
+        level.pop().unwrap_or_else(identity)
+    }
+
+    /// Folds the inputs into one accumulator per worker, starting from
+    /// `identity`, then merges the accumulators with `combine`.
+    #[inline]
+    pub fn fold<A, T, ID, F, C>(&self, inputs: &[A], identity: ID, fold: F, combine: C) -> T
+    where
+        A: Sync,
+        T: Send,
+        ID: Fn() -> T + Send + Sync,
+        F: Fn(T, &A) -> T + Send + Sync,
+        C: Fn(T, T) -> T,
+    {
+        self.partials(inputs.len(), |indices| {
+            indices.fold(identity(), |acc, index| fold(acc, &inputs[index]))
+        })
+        .into_iter()
+        .fold(identity(), combine)
+    }
+
+    #[inline]
+    pub fn sum<A, S>(&self, inputs: &[A]) -> S
+    where
+        A: Sync,
+        S: Send + Sum<S> + for<'a> Sum<&'a A>,
+    {
+        self.partials(inputs.len(), |indices| {
+            indices.map(|index| &inputs[index]).sum::<S>()
+        })
+        .into_iter()
+        .sum()
+    }
+
+    /// Returns the first element with the smallest key, like Iterator::min_by_key
+    #[inline]
+    pub fn min_by_key<'a, A, K, F>(&self, inputs: &'a [A], key: F) -> Option<&'a A>
+    where
+        A: Sync,
+        K: Ord + Send,
+        F: Fn(&A) -> K + Send + Sync,
+    {
+        // Ties are broken on the index so the result doesn't depend on scheduling
+        self.partials(inputs.len(), |indices| {
+            indices.map(|index| (key(&inputs[index]), index)).min()
+        })
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|(_, index)| &inputs[index])
+    }
+
+    /// Returns the last element with the largest key, like Iterator::max_by_key
+    #[inline]
+    pub fn max_by_key<'a, A, K, F>(&self, inputs: &'a [A], key: F) -> Option<&'a A>
+    where
+        A: Sync,
+        K: Ord + Send,
+        F: Fn(&A) -> K + Send + Sync,
+    {
+        self.partials(inputs.len(), |indices| {
+            indices.map(|index| (key(&inputs[index]), index)).max()
+        })
+        .into_iter()
+        .flatten()
+        .max()
+        .map(|(_, index)| &inputs[index])
+    }
+
+    /// Runs `partial` once on every worker which claims work over the indices
+    /// it claims, and returns the partial results.
+    #[inline]
+    pub(crate) fn partials<T, P>(&self, num: usize, partial: P) -> Vec<T>
+    where
+        T: Send,
+        P: Fn(&mut Indices) -> T + Send + Sync,
+    {
+        let partials = Mutex::new(Vec::with_capacity(self.num_threads));
+        let runner = |indices: &mut Indices| {
+            let result = partial(indices);
+            partials.lock().push(result);
+        };
+        self.run(unsafe { Job::from_runner(num, runner, self.num_threads) });
+        partials.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reduce() {
+        let pool = Pool::default();
+        let array: Vec<usize> = (0..10_000).collect();
+        let total = pool.reduce(&array, || 0, |val| val * 2, |a, b| a + b);
+        assert_eq!(total, 10_000 * 9_999);
+
+        let empty: [usize; 0] = [];
+        assert_eq!(pool.reduce(&empty, || 7, |val| *val, |a, b| a + b), 7);
+    }
+
+    #[test]
+    fn test_fold() {
+        let pool = Pool::default();
+        let array: Vec<usize> = (0..10_000).collect();
+        let evens = pool.fold(
+            &array,
+            Vec::new,
+            |mut acc, val| {
+                if val % 2 == 0 {
+                    acc.push(*val);
+                }
+                acc
+            },
+            |mut a, mut b| {
+                a.append(&mut b);
+                a
+            },
+        );
+        assert_eq!(evens.len(), 5_000);
+        assert!(evens.iter().all(|val| val % 2 == 0));
+    }
+
+    #[test]
+    fn test_sum_min_max() {
+        let pool = Pool::default();
+        let array: Vec<u64> = (1..=10_000).collect();
+        assert_eq!(pool.sum::<_, u64>(&array), 50_005_000);
+
+        let keys = [5usize, 3, 9, 3, 1, 9, 1, 4];
+        let min = pool.min_by_key(&keys, |val| *val).unwrap();
+        let max = pool.max_by_key(&keys, |val| *val).unwrap();
+        assert!(std::ptr::eq(min, &keys[4]));
+        assert!(std::ptr::eq(max, &keys[5]));
+        assert_eq!(pool.min_by_key(&keys[..0], |val| *val), None);
+    }
+
+    #[test]
+    fn test_reduce_deterministic() {
+        let array: Vec<f64> = (0..100_000)
+            .map(|i| ((i * 7919) % 1000) as f64 * 1e-3 + 1e10 * ((i % 3) as f64 - 1.0))
+            .collect();
+        let sum = |pool: &Pool| {
+            pool.reduce_deterministic(&array, || 0.0, |val| *val, |a, b| a + b)
+                .to_bits()
+        };
+
+        let expected = sum(&Pool::new(1));
+        for &threads in [1, 2, Pool::get_thread_count()].iter() {
+            let pool = Pool::new(threads);
+            for _ in 0..5 {
+                assert_eq!(sum(&pool), expected);
+            }
+        }
+
+        let pool = Pool::new(3);
+        let empty: [f64; 0] = [];
+        assert_eq!(
+            pool.reduce_deterministic(&empty, || 1.5, |val| *val, |a, b| a + b),
+            1.5
+        );
+    }
+}
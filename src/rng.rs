@@ -0,0 +1,93 @@
+use crate::job::SendPtr;
+use crate::pool::Pool;
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Counter based random number generator. Each value is a hash of the seed,
+/// the element index and the number of values drawn before it, so the
+/// stream of an element never depends on which worker processed it.
+/// Not suitable for cryptography.
+#[derive(Debug, Clone)]
+pub struct IndexRng {
+    key: u64,
+    counter: u64,
+}
+
+impl IndexRng {
+    #[inline]
+    pub fn new(seed: u64, index: u64) -> Self {
+        Self {
+            key: mix(seed ^ mix(index.wrapping_add(GOLDEN_GAMMA))),
+            counter: 0,
+        }
+    }
+
+    #[inline]
+    pub fn next_u64(&mut self) -> u64 {
+        self.counter = self.counter.wrapping_add(1);
+        mix(self
+            .key
+            .wrapping_add(self.counter.wrapping_mul(GOLDEN_GAMMA)))
+    }
+
+    #[inline]
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Uniform in `[0, 1)`
+    #[inline]
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+}
+
+/// SplitMix64 finalizer
+#[inline]
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl Pool {
+    /// Like dispatch_mut but also hands `func` a generator seeded from
+    /// `seed` and the index of the element, so results are reproducible
+    /// whatever the thread count or scheduling.
+    #[inline]
+    pub fn dispatch_mut_with_rng<F, A>(&self, elems: &mut [A], seed: u64, func: F)
+    where
+        A: Send,
+        F: Fn(&mut IndexRng, &mut A) + Send + Sync,
+    {
+        let ptr = SendPtr(elems.as_mut_ptr());
+        self.dispatch_fn(elems.len(), move |index| {
+            let mut rng = IndexRng::new(seed, index as u64);
+            func(&mut rng, unsafe { ptr.get(index) })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispatch_with_rng() {
+        let simulate = |pool: &Pool, seed| {
+            let mut array = vec![0.0f64; 10_000];
+            pool.dispatch_mut_with_rng(&mut array, seed, |rng, val: &mut f64| {
+                *val = (0..10).map(|_| rng.next_f64()).sum();
+            });
+            array
+        };
+
+        let expected = simulate(&Pool::new(1), 42);
+        assert_eq!(simulate(&Pool::new(4), 42), expected);
+        assert_ne!(simulate(&Pool::new(4), 43), expected);
+
+        let mean = expected.iter().sum::<f64>() / (expected.len() * 10) as f64;
+        assert!((mean - 0.5).abs() < 0.01);
+        assert_ne!(expected[0], expected[1]);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::job::{Job, SendPtr};
+use crate::pool::Pool;
+
+impl Pool {
+    /// Replaces every element with `op` applied over it and all elements
+    /// before it. `op` must be associative.
+    #[inline]
+    pub fn scan_inclusive<T, F>(&self, elems: &mut [T], op: F)
+    where
+        T: Clone + Send + Sync,
+        F: Fn(&T, &T) -> T + Send + Sync,
+    {
+        self.scan(elems, None, op);
+    }
+
+    /// Replaces every element with `op` applied over all elements before it,
+    /// starting from `identity`. Returns `op` applied over all elements.
+    #[inline]
+    pub fn scan_exclusive<T, F>(&self, elems: &mut [T], identity: T, op: F) -> T
+    where
+        T: Clone + Send + Sync,
+        F: Fn(&T, &T) -> T + Send + Sync,
+    {
+        self.scan(elems, Some(&identity), op).unwrap_or(identity)
+    }
+
+    /// Two pass blocked scan over the same ranges Job uses for its batches.
+    /// The first pass scans each block on its own and records its total,
+    /// the block totals are scanned in order and the second pass folds the
+    /// total of all earlier blocks into each block.
+    fn scan<T, F>(&self, elems: &mut [T], identity: Option<&T>, op: F) -> Option<T>
+    where
+        T: Clone + Send + Sync,
+        F: Fn(&T, &T) -> T + Send + Sync,
+    {
+        let blocks: Vec<(usize, usize)> = Job::batch_ranges(elems.len(), self.num_threads)
+            .filter(|&(count, _)| count > 0)
+            .collect();
+        let mut totals: Vec<Option<T>> = Vec::with_capacity(blocks.len());
+        totals.resize_with(blocks.len(), || None);
+
+        let ptr = SendPtr(elems.as_mut_ptr());
+        let totals_ptr = SendPtr(totals.as_mut_ptr());
+        self.dispatch_fn(blocks.len(), |block| {
+            let (count, offset) = blocks[block];
+            // Blocks never overlap and every block index is handed out once
+            let block_elems = unsafe { std::slice::from_raw_parts_mut(ptr.0.add(offset), count) };
+            let total = match identity {
+                Some(identity) => {
+                    let mut acc = identity.clone();
+                    for elem in block_elems.iter_mut() {
+                        let next = op(&acc, elem);
+                        *elem = std::mem::replace(&mut acc, next);
+                    }
+                    acc
+                }
+                None => {
+                    for i in 1..count {
+                        block_elems[i] = op(&block_elems[i - 1], &block_elems[i]);
+                    }
+                    block_elems[count - 1].clone()
+                }
+            };
+            *unsafe { totals_ptr.get(block) } = Some(total);
+        });
+
+        // Turn the block totals into the total of every block before it
+        let mut carry: Option<T> = None;
+        let carries: Vec<Option<T>> = totals
+            .into_iter()
+            .map(|total| {
+                let total = total.unwrap();
+                let next = match &carry {
+                    Some(carry) => op(carry, &total),
+                    None => total,
+                };
+                carry.replace(next)
+            })
+            .collect();
+
+        self.dispatch_fn(blocks.len(), |block| {
+            if let Some(carry) = &carries[block] {
+                let (count, offset) = blocks[block];
+                let block_elems =
+                    unsafe { std::slice::from_raw_parts_mut(ptr.0.add(offset), count) };
+                for elem in block_elems.iter_mut() {
+                    *elem = op(carry, elem);
+                }
+            }
+        });
+
+        carry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_inclusive() {
+        let pool = Pool::new(3);
+        for &len in [0, 1, 5, 23, 10_000].iter() {
+            let mut array: Vec<usize> = (0..len).map(|i| i % 7).collect();
+            let mut expected = array.clone();
+            for i in 1..len {
+                expected[i] += expected[i - 1];
+            }
+            pool.scan_inclusive(&mut array, |a, b| a + b);
+            assert_eq!(array, expected);
+        }
+    }
+
+    #[test]
+    fn test_scan_exclusive() {
+        let pool = Pool::new(3);
+        let mut array: Vec<String> = (0..1000).map(|i| (i % 10).to_string()).collect();
+        let expected: Vec<String> = (0..1000)
+            .map(|i| (0..i).map(|j| (j % 10).to_string()).collect())
+            .collect();
+        let total = pool.scan_exclusive(&mut array, String::new(), |a, b| a.clone() + b);
+        assert_eq!(array, expected);
+        assert_eq!(total.len(), 1000);
+
+        let mut empty: [u32; 0] = [];
+        assert_eq!(pool.scan_exclusive(&mut empty, 0, |a, b| a + b), 0);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::job::{Indices, Job};
+use crate::pool::Pool;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const NOT_FOUND: usize = usize::MAX;
+
+impl Pool {
+    /// Returns true if any input matches `pred`, stops at the first match
+    #[inline]
+    pub fn any<A, P>(&self, inputs: &[A], pred: P) -> bool
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        self.search(inputs, pred, false).is_some()
+    }
+
+    /// Returns true if every input matches `pred`, stops at the first miss
+    #[inline]
+    pub fn all<A, P>(&self, inputs: &[A], pred: P) -> bool
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        self.search(inputs, |elem| !pred(elem), false).is_none()
+    }
+
+    /// Returns some input matching `pred`, not necessarily the first one
+    #[inline]
+    pub fn find_any<'a, A, P>(&self, inputs: &'a [A], pred: P) -> Option<&'a A>
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        self.search(inputs, pred, false).map(|index| &inputs[index])
+    }
+
+    /// Returns the first input matching `pred`
+    #[inline]
+    pub fn find_first<'a, A, P>(&self, inputs: &'a [A], pred: P) -> Option<&'a A>
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        self.position(inputs, pred).map(|index| &inputs[index])
+    }
+
+    /// Returns the lowest index of an input matching `pred`
+    #[inline]
+    pub fn position<A, P>(&self, inputs: &[A], pred: P) -> Option<usize>
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        self.search(inputs, pred, true)
+    }
+
+    /// Looks for a match and cancels the remaining work once one is found.
+    /// When `first` is set only the batches above the match are cancelled,
+    /// the ones below still finish in case they hold an earlier match.
+    fn search<A, P>(&self, inputs: &[A], pred: P, first: bool) -> Option<usize>
+    where
+        A: Sync,
+        P: Fn(&A) -> bool + Send + Sync,
+    {
+        let found = AtomicUsize::new(NOT_FOUND);
+        let runner = |indices: &mut Indices| {
+            while let Some(index) = indices.next() {
+                // Only the rest of a batch holding a match can be above it
+                if first && index > found.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if pred(&inputs[index]) {
+                    found.fetch_min(index, Ordering::Relaxed);
+                    if first {
+                        indices.cancel_after(index);
+                    } else {
+                        indices.cancel();
+                    }
+                }
+            }
+        };
+        self.run(unsafe { Job::from_runner(inputs.len(), runner, self.num_threads) });
+
+        match found.into_inner() {
+            NOT_FOUND => None,
+            index => Some(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_first() {
+        let pool = Pool::new(4);
+        let array: Vec<usize> = (0..100_000).collect();
+        for &target in [0, 1, 777, 50_000, 99_999].iter() {
+            let calls = AtomicUsize::new(0);
+            let found = pool.position(&array, |val| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                *val >= target && val % 3 == target % 3
+            });
+            assert_eq!(found, Some(target));
+            if target < 50_000 {
+                assert!(calls.load(Ordering::Relaxed) < array.len());
+            }
+        }
+        assert_eq!(pool.find_first(&array, |val| *val > 10), Some(&11));
+        assert_eq!(pool.position(&array, |val| *val > 100_000), None);
+    }
+
+    #[test]
+    fn test_any_all() {
+        let pool = Pool::new(4);
+        let array: Vec<usize> = (0..100_000).collect();
+        assert!(pool.any(&array, |val| *val == 4242));
+        assert!(!pool.any(&array, |val| *val == 100_000));
+        assert!(pool.all(&array, |val| *val < 100_000));
+        assert!(!pool.all(&array, |val| *val != 31));
+        assert!(pool.all(&array[..0], |_| false));
+
+        let found = pool.find_any(&array, |val| val % 1000 == 999).unwrap();
+        assert_eq!(found % 1000, 999);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::job::SendPtr;
+use crate::pool::Pool;
+
+use std::cmp::{self, Ordering};
+use std::ptr;
+
+/// Inputs up to this size are sorted on the calling thread, it is also
+/// the smallest run or merge segment handed to a worker.
+const SORT_RUN: usize = 4096;
+
+impl Pool {
+    /// Stable parallel sort, see slice::sort
+    #[inline]
+    pub fn sort<T>(&self, elems: &mut [T])
+    where
+        T: Ord + Send + Sync,
+    {
+        self.merge_sort(elems, T::cmp, true);
+    }
+
+    /// Stable parallel sort, see slice::sort_by
+    #[inline]
+    pub fn sort_by<T, F>(&self, elems: &mut [T], compare: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        self.merge_sort(elems, compare, true);
+    }
+
+    /// Stable parallel sort, see slice::sort_by_key
+    #[inline]
+    pub fn sort_by_key<T, K, F>(&self, elems: &mut [T], key: F)
+    where
+        T: Send + Sync,
+        K: Ord,
+        F: Fn(&T) -> K + Sync,
+    {
+        self.merge_sort(elems, |a, b| key(a).cmp(&key(b)), true);
+    }
+
+    /// Parallel sort which may reorder equal elements, see slice::sort_unstable
+    #[inline]
+    pub fn sort_unstable<T>(&self, elems: &mut [T])
+    where
+        T: Ord + Send + Sync,
+    {
+        self.merge_sort(elems, T::cmp, false);
+    }
+
+    /// Parallel sort which may reorder equal elements, see slice::sort_unstable_by
+    #[inline]
+    pub fn sort_unstable_by<T, F>(&self, elems: &mut [T], compare: F)
+    where
+        T: Send + Sync,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        self.merge_sort(elems, compare, false);
+    }
+
+    /// Sorts runs of the input in parallel, then merges pairs of runs until
+    /// one is left. Every merge is split into independent segments so all
+    /// workers stay busy in the last rounds too.
+    fn merge_sort<T, F>(&self, elems: &mut [T], compare: F, stable: bool)
+    where
+        T: Send + Sync,
+        F: Fn(&T, &T) -> Ordering + Sync,
+    {
+        let len = elems.len();
+        let segments = self.num_threads * 4;
+        if len <= SORT_RUN || self.num_threads == 1 {
+            if stable {
+                elems.sort_by(compare);
+            } else {
+                elems.sort_unstable_by(compare);
+            }
+            return;
+        }
+
+        let run_len = cmp::max(len.div_ceil(segments), SORT_RUN);
+        let runs = len.div_ceil(run_len);
+        let ptr = SendPtr(elems.as_mut_ptr());
+        self.dispatch_fn(runs, |run| {
+            let start = run * run_len;
+            let end = cmp::min(start + run_len, len);
+            // Runs never overlap and elems outlives the job
+            let run = unsafe { std::slice::from_raw_parts_mut(ptr.0.add(start), end - start) };
+            if stable {
+                run.sort_by(&compare);
+            } else {
+                run.sort_unstable_by(&compare);
+            }
+        });
+
+        // Elements are moved bitwise between elems and buf, buf never owns them
+        let mut buf: Vec<T> = Vec::with_capacity(len);
+        let mut dst = SendPtr(buf.as_mut_ptr());
+        // Declared after buf so it moves the elements back before buf is freed
+        let mut merged = MergeGuard {
+            src: ptr,
+            elems: ptr,
+            len,
+        };
+        let seg_len = cmp::max(len.div_ceil(segments), SORT_RUN);
+
+        let mut width = run_len;
+        while width < len {
+            let pairs = len.div_ceil(2 * width);
+            let segs_per_pair = (2 * width).div_ceil(seg_len);
+            self.dispatch_fn(pairs * segs_per_pair, |task| {
+                let start = (task / segs_per_pair) * 2 * width;
+                let mid = cmp::min(start + width, len);
+                let end = cmp::min(start + 2 * width, len);
+                let out_start = cmp::min(start + (task % segs_per_pair) * seg_len, end);
+                let out_end = cmp::min(out_start + seg_len, end);
+                if out_start == out_end {
+                    return;
+                }
+                let src = merged.src;
+                // Each task writes a disjoint part of dst and only reads src
+                unsafe {
+                    let left = std::slice::from_raw_parts(src.0.add(start), mid - start);
+                    let right = std::slice::from_raw_parts(src.0.add(mid), end - mid);
+                    merge_segment(
+                        left,
+                        right,
+                        (out_start - start, out_end - start),
+                        dst.0.add(out_start),
+                        &compare,
+                    );
+                }
+            });
+            std::mem::swap(&mut merged.src, &mut dst);
+            width *= 2;
+        }
+    }
+}
+
+/// Holds the buffer with the latest complete copy of the elements during a
+/// merge sort and moves it back into the input when dropped. A panicking
+/// compare leaves the round it panicked in half written, the previous
+/// round still has every element exactly once.
+struct MergeGuard<T> {
+    src: SendPtr<T>,
+    elems: SendPtr<T>,
+    len: usize,
+}
+
+impl<T> Drop for MergeGuard<T> {
+    fn drop(&mut self) {
+        if self.src.0 != self.elems.0 {
+            unsafe { ptr::copy_nonoverlapping(self.src.0, self.elems.0, self.len) };
+        }
+    }
+}
+
+/// Returns how many elements of `left` are among the first `rank` elements
+/// of the stable merge of `left` and `right`. Ties are taken from `left`.
+fn co_rank<T, F>(rank: usize, left: &[T], right: &[T], compare: &F) -> usize
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let mut lo = rank.saturating_sub(right.len());
+    let mut hi = cmp::min(rank, left.len());
+    while lo < hi {
+        let i = (lo + hi) / 2;
+        let j = rank - i;
+        if j > 0 && i < left.len() && compare(&right[j - 1], &left[i]) != Ordering::Less {
+            lo = i + 1;
+        } else {
+            hi = i;
+        }
+    }
+    lo
+}
+
+/// Writes elements `out.0..out.1` of the stable merge of `left` and `right`
+/// to `dst`. Unsafe because the elements are moved bitwise into `dst`.
+unsafe fn merge_segment<T, F>(
+    left: &[T],
+    right: &[T],
+    (out_start, out_end): (usize, usize),
+    mut dst: *mut T,
+    compare: &F,
+) where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let mut i = co_rank(out_start, left, right, compare);
+    let mut j = out_start - i;
+    let i_end = co_rank(out_end, left, right, compare);
+    let j_end = out_end - i_end;
+
+    while i < i_end && j < j_end {
+        let from = if compare(&right[j], &left[i]) == Ordering::Less {
+            j += 1;
+            &right[j - 1]
+        } else {
+            i += 1;
+            &left[i - 1]
+        };
+        ptr::copy_nonoverlapping(from, dst, 1);
+        dst = dst.add(1);
+    }
+    ptr::copy_nonoverlapping(left.as_ptr().add(i), dst, i_end - i);
+    dst = dst.add(i_end - i);
+    ptr::copy_nonoverlapping(right.as_ptr().add(j), dst, j_end - j);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shuffled(len: usize) -> Vec<u64> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state % (len as u64 / 4 + 1)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        let pool = Pool::new(4);
+        for &len in [0, 1, 100, SORT_RUN + 1, 100_000, 250_003].iter() {
+            let mut array = shuffled(len);
+            let mut expected = array.clone();
+            expected.sort();
+
+            pool.sort(&mut array);
+            assert_eq!(array, expected);
+
+            let mut array = shuffled(len);
+            pool.sort_unstable(&mut array);
+            assert_eq!(array, expected);
+        }
+    }
+
+    #[test]
+    fn test_sort_panic() {
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+        let pool = Pool::new(4);
+        let mut array: Vec<String> = shuffled(50_000).iter().map(u64::to_string).collect();
+        let mut expected = array.clone();
+        expected.sort();
+
+        let compares = AtomicUsize::new(0);
+        let counted = |a: &String, b: &String| {
+            compares.fetch_add(1, AtomicOrdering::Relaxed);
+            a.cmp(b)
+        };
+        pool.sort_by(&mut array.clone(), counted);
+        // Panics in the last merge round, which writes into the input
+        let panic_at = compares.swap(0, AtomicOrdering::Relaxed) * 99 / 100;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.sort_by(&mut array, |a, b| {
+                if compares.fetch_add(1, AtomicOrdering::Relaxed) == panic_at {
+                    panic!("compare failed");
+                }
+                a.cmp(b)
+            })
+        }));
+        assert!(result.is_err());
+
+        // Every element is still there exactly once
+        array.sort();
+        assert_eq!(array, expected);
+    }
+
+    #[test]
+    fn test_sort_stable() {
+        let pool = Pool::new(3);
+        let mut array: Vec<(u64, usize)> = shuffled(200_000)
+            .into_iter()
+            .enumerate()
+            .map(|(i, key)| (key % 100, i))
+            .collect();
+        let mut expected = array.clone();
+        expected.sort_by_key(|elem| elem.0);
+
+        pool.sort_by_key(&mut array, |elem| elem.0);
+        assert_eq!(array, expected);
+
+        pool.sort_unstable_by(&mut array, |a, b| b.cmp(a));
+        assert!(array.windows(2).all(|pair| pair[0] >= pair[1]));
+    }
+}
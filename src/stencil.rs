@@ -0,0 +1,244 @@
+use crate::job::SendPtr;
+use crate::pool::Pool;
+
+use std::cmp;
+use std::mem;
+use std::ops::Deref;
+
+/// Read only neighbourhood of one element in a 1D stencil. Derefs to
+/// the source elements within `radius` of the center, clipped at the
+/// ends of the source slice.
+pub struct Window<'a, A> {
+    elems: &'a [A],
+    center: usize,
+}
+
+impl<'a, A> Window<'a, A> {
+    #[inline]
+    fn new(src: &'a [A], index: usize, radius: usize) -> Self {
+        let start = index.saturating_sub(radius);
+        let end = cmp::min(index.saturating_add(radius).saturating_add(1), src.len());
+        Self {
+            elems: &src[start..end],
+            center: index - start,
+        }
+    }
+
+    /// Position of the center element within the window
+    #[inline]
+    pub fn center(&self) -> usize {
+        self.center
+    }
+
+    /// Element `offset` away from the center, None if it is clipped
+    #[inline]
+    pub fn get(&self, offset: isize) -> Option<&'a A> {
+        let index = self.center as isize + offset;
+        if index < 0 {
+            return None;
+        }
+        self.elems.get(index as usize)
+    }
+}
+
+impl<'a, A> Deref for Window<'a, A> {
+    type Target = [A];
+
+    #[inline]
+    fn deref(&self) -> &[A] {
+        self.elems
+    }
+}
+
+/// Read only neighbourhood of one element in a row-major 2D stencil
+pub struct Window2<'a, A> {
+    src: &'a [A],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    radius: usize,
+}
+
+impl<'a, A> Window2<'a, A> {
+    /// Column of the center element in the grid
+    #[inline]
+    pub fn x(&self) -> usize {
+        self.x
+    }
+
+    /// Row of the center element in the grid
+    #[inline]
+    pub fn y(&self) -> usize {
+        self.y
+    }
+
+    #[inline]
+    pub fn center(&self) -> &'a A {
+        &self.src[self.y * self.width + self.x]
+    }
+
+    /// Element `dx`, `dy` away from the center. None outside the grid
+    /// or further than `radius` away in either direction.
+    #[inline]
+    pub fn get(&self, dx: isize, dy: isize) -> Option<&'a A> {
+        let radius = self.radius as isize;
+        if dx.abs() > radius || dy.abs() > radius {
+            return None;
+        }
+        let x = self.x as isize + dx;
+        let y = self.y as isize + dy;
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+        Some(&self.src[y as usize * self.width + x as usize])
+    }
+}
+
+/// Two equally sized buffers for iterated stencils. Every step reads the
+/// front buffer, writes the back buffer and then swaps them.
+#[derive(Debug, Clone)]
+pub struct DoubleBuffer<A> {
+    front: Vec<A>,
+    back: Vec<A>,
+}
+
+impl<A: Clone> DoubleBuffer<A> {
+    #[inline]
+    pub fn new(front: Vec<A>) -> Self {
+        let back = front.clone();
+        Self { front, back }
+    }
+}
+
+impl<A> DoubleBuffer<A> {
+    /// Holds the result of the last step
+    #[inline]
+    pub fn front(&self) -> &[A] {
+        &self.front
+    }
+
+    #[inline]
+    pub fn front_mut(&mut self) -> &mut [A] {
+        &mut self.front
+    }
+
+    /// Runs `step` from the front buffer into the back buffer, then swaps
+    #[inline]
+    pub fn step<F>(&mut self, step: F)
+    where
+        F: FnOnce(&[A], &mut [A]),
+    {
+        step(&self.front, &mut self.back);
+        mem::swap(&mut self.front, &mut self.back);
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> Vec<A> {
+        self.front
+    }
+}
+
+impl Pool {
+    /// Writes every element of `dst` from the neighbourhood of the same
+    /// index in `src`, every element within `radius` of it.
+    #[inline]
+    pub fn stencil<A, F>(&self, src: &[A], dst: &mut [A], radius: usize, func: F)
+    where
+        A: Send + Sync,
+        F: Fn(&Window<A>, &mut A) + Send + Sync,
+    {
+        assert_eq!(src.len(), dst.len());
+
+        let ptr = SendPtr(dst.as_mut_ptr());
+        self.dispatch_fn(dst.len(), move |index| {
+            func(&Window::new(src, index, radius), unsafe { ptr.get(index) })
+        });
+    }
+
+    /// 2D version of stencil over row-major `width` x `height` grids
+    #[inline]
+    pub fn stencil_2d<A, F>(
+        &self,
+        src: &[A],
+        dst: &mut [A],
+        (width, height): (usize, usize),
+        radius: usize,
+        func: F,
+    ) where
+        A: Send + Sync,
+        F: Fn(&Window2<A>, &mut A) + Send + Sync,
+    {
+        assert_eq!(src.len(), width * height);
+        assert_eq!(dst.len(), width * height);
+
+        let ptr = SendPtr(dst.as_mut_ptr());
+        self.dispatch_fn(dst.len(), move |index| {
+            let window = Window2 {
+                src,
+                width,
+                height,
+                x: index % width,
+                y: index / width,
+                radius,
+            };
+            func(&window, unsafe { ptr.get(index) })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stencil() {
+        let pool = Pool::new(3);
+        let src: Vec<usize> = (0..1000).collect();
+        let mut dst = vec![0; 1000];
+        pool.stencil(&src, &mut dst, 2, |window, out| *out = window.iter().sum());
+        assert_eq!(dst[0], 1 + 2);
+        assert_eq!(dst[1], 1 + 2 + 3);
+        assert_eq!(dst[500], 498 + 499 + 500 + 501 + 502);
+        assert_eq!(dst[999], 997 + 998 + 999);
+
+        let window = Window::new(&src, 1, 2);
+        assert_eq!(window.center(), 1);
+        assert_eq!(window.get(-1), Some(&0));
+        assert_eq!(window.get(-2), None);
+        assert_eq!(window.get(2), Some(&3));
+    }
+
+    #[test]
+    fn test_stencil_2d_life() {
+        let pool = Pool::new(3);
+        let (width, height) = (5, 5);
+        let mut cells = vec![false; width * height];
+        for x in 1..4 {
+            cells[2 * width + x] = true;
+        }
+        let start = cells.clone();
+
+        let life = |window: &Window2<bool>, out: &mut bool| {
+            let mut alive = 0;
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    if (dx, dy) != (0, 0) && window.get(dx, dy) == Some(&true) {
+                        alive += 1;
+                    }
+                }
+            }
+            *out = alive == 3 || (alive == 2 && *window.center());
+        };
+
+        let mut buffers = DoubleBuffer::new(cells);
+        buffers.step(|src, dst| pool.stencil_2d(src, dst, (width, height), 1, life));
+        for y in 1..4 {
+            assert!(buffers.front()[y * width + 2]);
+        }
+        assert!(!buffers.front()[2 * width + 1]);
+
+        buffers.step(|src, dst| pool.stencil_2d(src, dst, (width, height), 1, life));
+        assert_eq!(buffers.into_inner(), start);
+    }
+}
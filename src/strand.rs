@@ -0,0 +1,129 @@
+use crate::pool::{Pool, Task};
+use crate::task::{packet_task, JoinHandle};
+
+use parking_lot::Mutex;
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+/// Per key queues of Pool::spawn_keyed. A key has a queue for as long as
+/// one of its tasks is running, and that task's worker drains the queue.
+pub(crate) struct Strands {
+    hasher: RandomState,
+    queues: Mutex<HashMap<u64, VecDeque<Task>>>,
+}
+
+impl fmt::Debug for Strands {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Strands")
+            .field("active", &self.queues.lock().len())
+            .finish()
+    }
+}
+
+impl Strands {
+    pub(crate) fn new() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `task` and then every task queued behind it for the same key
+    fn run(&self, hash: u64, mut task: Task) {
+        loop {
+            task();
+            let mut queues = self.queues.lock();
+            let next = queues.get_mut(&hash).and_then(VecDeque::pop_front);
+            match next {
+                Some(next) => task = next,
+                None => {
+                    queues.remove(&hash);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Pool {
+    /// Like spawn, but tasks with the same key run one after another in
+    /// the order they were spawned. Tasks with different keys run in
+    /// parallel, spread over the workers. Keys are told apart by hash, so
+    /// two keys with the same hash share a strand.
+    pub fn spawn_keyed<K, F, T>(&self, key: K, func: F) -> JoinHandle<T>
+    where
+        K: Hash,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // A panic is caught by the task, so it can't stop the strand either
+        let (packet, task) = packet_task(func);
+
+        let hash = self.strands.hasher.hash_one(&key);
+        let mut queues = self.strands.queues.lock();
+        match queues.entry(hash) {
+            // The worker running this key picks it up once it's done
+            Entry::Occupied(mut queue) => queue.get_mut().push_back(task),
+            Entry::Vacant(queue) => {
+                queue.insert(VecDeque::new());
+                drop(queues);
+                let strands = self.strands.clone();
+                self.send_task(Box::new(move || strands.run(hash, task)));
+            }
+        }
+        JoinHandle::new(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_spawn_keyed() {
+        let pool = Pool::new(4);
+        let accounts = 8;
+        let logs: Arc<Vec<Mutex<Vec<usize>>>> =
+            Arc::new((0..accounts).map(|_| Mutex::new(Vec::new())).collect());
+        let running: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..accounts).map(|_| AtomicUsize::new(0)).collect());
+
+        let handles: Vec<_> = (0..400)
+            .map(|event| {
+                let account = event % accounts;
+                let logs = logs.clone();
+                let running = running.clone();
+                pool.spawn_keyed(account, move || {
+                    // Tasks of one account never overlap
+                    let overlap = running[account].fetch_add(1, Ordering::SeqCst);
+                    if event % 50 == 0 {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    logs[account].lock().push(event);
+                    running[account].fetch_sub(1, Ordering::SeqCst);
+                    overlap
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 0);
+        }
+
+        for (account, log) in logs.iter().enumerate() {
+            let expected: Vec<usize> = (0..400).filter(|e| e % accounts == account).collect();
+            assert_eq!(*log.lock(), expected);
+        }
+
+        // A panicking task doesn't stall the rest of its strand
+        let failed = pool.spawn_keyed("key", || panic!("task failed"));
+        let next = pool.spawn_keyed("key", || 1);
+        assert!(failed.join().is_err());
+        assert_eq!(next.join().unwrap(), 1);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::job::SendPtr;
+use crate::pool::{Pool, Task};
+
+use crossbeam_deque::Injector;
+use parking_lot::{Condvar, Mutex};
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Result slot shared by a spawned task and its handle
+pub(crate) struct Packet<T> {
+    result: Mutex<Option<thread::Result<T>>>,
+    done: Condvar,
+}
+
+impl<T> Packet<T> {
+    #[inline]
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Packet {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn set(&self, result: thread::Result<T>) {
+        *self.result.lock() = Some(result);
+        self.done.notify_all();
+    }
+}
+
+/// Runs a closure on a worker. A panic must not take the worker thread
+/// down with it, so it is caught and returned instead.
+#[inline]
+pub(crate) fn catch_panic<F, T>(func: F) -> thread::Result<T>
+where
+    F: FnOnce() -> T,
+{
+    panic::catch_unwind(AssertUnwindSafe(func))
+}
+
+/// Worker task running `func` and the packet it leaves the result in
+pub(crate) fn packet_task<F, T>(func: F) -> (Arc<Packet<T>>, Task)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Packet::new();
+    let task_packet = packet.clone();
+    let task: Task = Box::new(move || task_packet.set(catch_panic(func)));
+    (packet, task)
+}
+
+/// Owned permission to join a spawned task. Dropping the handle detaches
+/// the task, it still runs to completion but its result is discarded.
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    #[inline]
+    pub(crate) fn new(packet: Arc<Packet<T>>) -> Self {
+        JoinHandle { packet }
+    }
+
+    /// Waits for the task to finish. Returns Err with the panic payload
+    /// if the task panicked.
+    pub fn join(self) -> thread::Result<T> {
+        let mut result = self.packet.result.lock();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            self.packet.done.wait(&mut result);
+        }
+    }
+
+    /// True once the task returned or panicked
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().is_some()
+    }
+
+    /// Lets the task run in the background without joining it
+    #[inline]
+    pub fn detach(self) {}
+}
+
+/// Tasks of one scope, shared with the workers running them
+struct ScopeState {
+    queue: Injector<Task>,
+    /// Tasks spawned but not finished yet
+    pending: Mutex<usize>,
+    /// Signalled when a task is spawned or finishes
+    changed: Condvar,
+    /// Payload of the first task that panicked
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    #[inline]
+    fn pop(&self) -> Option<Task> {
+        loop {
+            let stolen = self.queue.steal();
+            if !stolen.is_retry() {
+                return stolen.success();
+            }
+        }
+    }
+}
+
+/// Spawns tasks which may borrow anything that outlives the scope.
+/// Created by Pool::scope.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope Pool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Runs `func` on the pool, or on the thread waiting for the scope
+    /// if it gets to it first. Pool::scope does not return before `func`
+    /// finished.
+    pub fn spawn<F>(&'scope self, func: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let state = self.state.clone();
+        let task: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            if let Err(payload) = catch_panic(func) {
+                state.panic.lock().get_or_insert(payload);
+            }
+            let mut pending = state.pending.lock();
+            *pending -= 1;
+            state.changed.notify_all();
+        });
+        // Pool::scope waits for every task it counted as pending, so
+        // nothing borrowed by the task is dropped before it ran
+        let task: Task = unsafe { mem::transmute(task) };
+
+        {
+            let mut pending = self.state.pending.lock();
+            *pending += 1;
+            self.state.queue.push(task);
+            self.state.changed.notify_all();
+        }
+
+        // Whoever gets the ticket runs the oldest queued task, if any is left
+        let state = self.state.clone();
+        self.pool.send_task(Box::new(move || {
+            if let Some(task) = state.pop() {
+                task();
+            }
+        }));
+    }
+
+    /// Helps running queued tasks until all of them finished
+    fn wait(&self) {
+        loop {
+            if let Some(task) = self.state.pop() {
+                task();
+                continue;
+            }
+            let mut pending = self.state.pending.lock();
+            if *pending == 0 {
+                return;
+            }
+            // Tasks are queued with the lock held, so none can slip by here
+            if self.state.queue.is_empty() {
+                self.state.changed.wait(&mut pending);
+            }
+        }
+    }
+}
+
+/// Second closure of Pool::join, run by whoever claims it first
+struct JoinState {
+    claimed: AtomicBool,
+    done: Mutex<bool>,
+    finished: Condvar,
+}
+
+impl JoinState {
+    #[inline]
+    fn claim(&self) -> bool {
+        !self.claimed.swap(true, Ordering::AcqRel)
+    }
+
+    #[inline]
+    fn set_done(&self) {
+        *self.done.lock() = true;
+        self.finished.notify_all();
+    }
+
+    #[inline]
+    fn wait(&self) {
+        let mut done = self.done.lock();
+        while !*done {
+            self.finished.wait(&mut done);
+        }
+    }
+}
+
+/// Closure and result slot living on the stack of Pool::join
+struct StackJob<F, R> {
+    func: Option<F>,
+    result: Option<thread::Result<R>>,
+}
+
+impl<F, R> StackJob<F, R>
+where
+    F: FnOnce() -> R,
+{
+    #[inline]
+    fn run(&mut self) {
+        if let Some(func) = self.func.take() {
+            self.result = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+        }
+    }
+}
+
+impl Pool {
+    /// Runs `oper_a` on the calling thread and offers `oper_b` to the
+    /// workers. If no worker picked up `oper_b` by the time `oper_a`
+    /// returns, the caller runs it as well. Panics of either closure are
+    /// resumed once both finished.
+    pub fn join<A, B, RA, RB>(&self, oper_a: A, oper_b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA,
+        B: FnOnce() -> RB + Send,
+        RB: Send,
+    {
+        let state = Arc::new(JoinState {
+            claimed: AtomicBool::new(false),
+            done: Mutex::new(false),
+            finished: Condvar::new(),
+        });
+        let mut job_b = StackJob {
+            func: Some(oper_b),
+            result: None,
+        };
+        let ptr = SendPtr(&mut job_b as *mut StackJob<B, RB>);
+
+        let ticket_state = state.clone();
+        let ticket: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+            // Only the winner of the claim may touch the caller's stack
+            if ticket_state.claim() {
+                unsafe { (*ptr.0).run() };
+                ticket_state.set_done();
+            }
+        });
+        // The ticket either loses the claim or the caller waits for it
+        let ticket: Task = unsafe { mem::transmute(ticket) };
+        self.send_task(ticket);
+
+        let result_a = panic::catch_unwind(AssertUnwindSafe(oper_a));
+        if state.claim() {
+            unsafe { (*ptr.0).run() };
+        } else {
+            state.wait();
+        }
+
+        let result_a = match result_a {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        match job_b.result {
+            Some(Ok(result_b)) => (result_a, result_b),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => unreachable!("join closure was claimed but never ran"),
+        }
+    }
+
+    /// Calls `func` with a scope for spawning tasks that borrow from the
+    /// stack, and returns once every task spawned in it has finished. If
+    /// `func` or any of the tasks panicked the panic is resumed here.
+    pub fn scope<'env, F, T>(&self, func: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                queue: Injector::new(),
+                pending: Mutex::new(0),
+                changed: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| func(&scope)));
+        scope.wait();
+
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        if let Some(payload) = scope.state.panic.lock().take() {
+            panic::resume_unwind(payload);
+        }
+        result
+    }
+
+    /// Runs `func` on one of the workers in the background. A panic in
+    /// `func` is caught and handed to JoinHandle::join.
+    pub fn spawn<F, T>(&self, func: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (packet, task) = packet_task(func);
+        self.send_task(task);
+        JoinHandle::new(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_spawn() {
+        let pool = Pool::new(2);
+        let handles: Vec<_> = (0..10usize)
+            .map(|i| pool.spawn(move || (0..=i).sum::<usize>()))
+            .collect();
+        let sums: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(sums, (0..10).map(|i| i * (i + 1) / 2).collect::<Vec<_>>());
+
+        let panicked = pool.spawn(|| panic!("task failed"));
+        assert!(panicked.join().is_err());
+
+        // Workers survive the panic and detached tasks still run
+        let count = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = channel();
+        for _ in 0..4 {
+            let count = count.clone();
+            let sender = sender.clone();
+            pool.spawn(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+                sender.send(()).unwrap();
+            })
+            .detach();
+        }
+        for _ in 0..4 {
+            receiver.recv().unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+
+        let handle = pool.spawn(|| 7);
+        while !handle.is_finished() {
+            thread::yield_now();
+        }
+        assert_eq!(handle.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_scope() {
+        let pool = Pool::new(2);
+        let mut array = vec![0usize; 1000];
+        let total = AtomicUsize::new(0);
+        let (left, right) = array.split_at_mut(500);
+
+        let value = pool.scope(|s| {
+            s.spawn(|| left.iter_mut().for_each(|val| *val = 1));
+            s.spawn(|| right.iter_mut().for_each(|val| *val = 2));
+            for i in 0..10 {
+                let total = &total;
+                s.spawn(move || {
+                    total.fetch_add(i, Ordering::Relaxed);
+                    s.spawn(move || {
+                        total.fetch_add(i, Ordering::Relaxed);
+                    });
+                });
+            }
+            42
+        });
+        assert_eq!(value, 42);
+        assert_eq!(array.iter().sum::<usize>(), 1500);
+        assert_eq!(total.load(Ordering::Relaxed), 90);
+
+        // Scopes opened from a worker must not wait on their own queue
+        let single = Arc::new(Pool::new(1));
+        let inner = single.clone();
+        let nested = single.spawn(move || {
+            let count = AtomicUsize::new(0);
+            inner.scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            });
+            count.into_inner()
+        });
+        assert_eq!(nested.join().unwrap(), 8);
+
+        let count = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("task failed"));
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_join() {
+        fn fib(pool: &Pool, n: u64) -> u64 {
+            if n < 2 {
+                return n;
+            }
+            let (a, b) = pool.join(|| fib(pool, n - 1), || fib(pool, n - 2));
+            a + b
+        }
+        let pool = Pool::new(4);
+        assert_eq!(fib(&pool, 20), 6765);
+
+        let mut array = vec![0usize; 1000];
+        let (left, right) = array.split_at_mut(500);
+        let (a, b) = pool.join(
+            || left.iter_mut().map(|val| *val = 1).count(),
+            || right.iter_mut().map(|val| *val = 2).count(),
+        );
+        assert_eq!((a, b), (500, 500));
+        assert_eq!(array.iter().sum::<usize>(), 1500);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.join(|| 1, || panic!("second closure failed"))
+        }));
+        assert!(result.is_err());
+    }
+}
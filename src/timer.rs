@@ -0,0 +1,257 @@
+use crate::pool::{Message, Pool, SendRef};
+use crate::task::catch_panic;
+
+use parking_lot::{Condvar, Mutex};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::{Duration, Instant};
+
+/// Closure of a scheduled task together with its cancellation state
+struct TimerTask {
+    func: Box<dyn Fn() + Send + Sync>,
+    period: Option<Duration>,
+    cancelled: AtomicBool,
+    /// Set while a run is queued or running, ticks in between are skipped
+    running: AtomicBool,
+}
+
+impl TimerTask {
+    fn run(&self) {
+        if !self.cancelled.load(Ordering::Acquire) {
+            let _ = catch_panic(|| (self.func)());
+        }
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+/// Heap entry, ordered so the earliest deadline is popped first
+struct Entry {
+    deadline: Instant,
+    seq: u64,
+    task: Arc<TimerTask>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+struct TimerState {
+    heap: BinaryHeap<Entry>,
+    seq: u64,
+    next_worker: usize,
+    shutdown: bool,
+}
+
+/// Timer thread which hands due tasks to the workers of a pool
+pub(crate) struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Timer")
+            .field("pending", &self.state.lock().heap.len())
+            .finish()
+    }
+}
+
+impl Timer {
+    /// Starts the timer thread, it stops once Timer::shutdown is called
+    pub(crate) fn start(senders: Vec<SendRef>) -> Arc<Timer> {
+        let timer = Arc::new(Timer {
+            state: Mutex::new(TimerState {
+                heap: BinaryHeap::new(),
+                seq: 0,
+                next_worker: 0,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_timer = timer.clone();
+        Builder::new()
+            .name("Timer thread".to_string())
+            .spawn(move || thread_timer.run(senders))
+            .unwrap();
+        timer
+    }
+
+    pub(crate) fn shutdown(&self) {
+        self.state.lock().shutdown = true;
+        self.changed.notify_all();
+    }
+
+    fn schedule(&self, deadline: Instant, task: Arc<TimerTask>) {
+        let mut state = self.state.lock();
+        let seq = state.seq;
+        state.seq += 1;
+        state.heap.push(Entry {
+            deadline,
+            seq,
+            task,
+        });
+        self.changed.notify_all();
+    }
+
+    fn run(&self, senders: Vec<SendRef>) {
+        let mut state = self.state.lock();
+        while !state.shutdown {
+            let deadline = match state.heap.peek() {
+                Some(entry) => entry.deadline,
+                None => {
+                    self.changed.wait(&mut state);
+                    continue;
+                }
+            };
+            if Instant::now() < deadline {
+                self.changed.wait_until(&mut state, deadline);
+                continue;
+            }
+
+            let entry = state.heap.pop().unwrap();
+            let task = entry.task;
+            if task.cancelled.load(Ordering::Acquire) {
+                continue;
+            }
+            if let Some(period) = task.period {
+                let seq = state.seq;
+                state.seq += 1;
+                state.heap.push(Entry {
+                    deadline: entry.deadline + period,
+                    seq,
+                    task: task.clone(),
+                });
+            }
+            if !task.running.swap(true, Ordering::AcqRel) {
+                let worker = state.next_worker % senders.len();
+                state.next_worker = state.next_worker.wrapping_add(1);
+                let _ = senders[worker].send(Message::Task(Box::new(move || task.run())));
+            }
+        }
+    }
+}
+
+/// Handle of a scheduled task. Dropping it leaves the task scheduled.
+pub struct TimerHandle {
+    task: Arc<TimerTask>,
+}
+
+impl TimerHandle {
+    /// Stops all future runs. A run that already started still finishes.
+    #[inline]
+    pub fn cancel(&self) {
+        self.task.cancelled.store(true, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.task.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Pool {
+    /// Runs `func` on one of the workers once `delay` has passed
+    pub fn schedule_after<F>(&self, delay: Duration, func: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let func = Mutex::new(Some(func));
+        self.schedule(delay, None, move || {
+            if let Some(func) = func.lock().take() {
+                func();
+            }
+        })
+    }
+
+    /// Runs `func` on one of the workers every `period`, starting one
+    /// period from now. A tick is skipped while the previous run of
+    /// `func` is still going, so runs never overlap.
+    pub fn schedule_every<F>(&self, period: Duration, func: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.schedule(period, Some(period), func)
+    }
+
+    fn schedule<F>(&self, delay: Duration, period: Option<Duration>, func: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let task = Arc::new(TimerTask {
+            func: Box::new(func),
+            period,
+            cancelled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+        });
+        self.timer().schedule(Instant::now() + delay, task.clone());
+        TimerHandle { task }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn test_schedule_after() {
+        let pool = Pool::new(2);
+        let (sender, receiver) = channel();
+        let start = Instant::now();
+        for &(delay, id) in [(30, 2), (10, 1), (50, 3)].iter() {
+            let sender = sender.clone();
+            pool.schedule_after(Duration::from_millis(delay), move || {
+                sender.send(id).unwrap()
+            });
+        }
+        let cancelled =
+            pool.schedule_after(Duration::from_millis(20), move || sender.send(0).unwrap());
+        cancelled.cancel();
+        assert!(cancelled.is_cancelled());
+
+        let order: Vec<usize> = receiver.iter().take(3).collect();
+        assert_eq!(order, vec![1, 2, 3]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn test_schedule_every() {
+        let pool = Pool::new(2);
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        let handle = pool.schedule_every(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        while ticks.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.cancel();
+        thread::sleep(Duration::from_millis(20));
+        let after_cancel = ticks.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(ticks.load(Ordering::SeqCst), after_cancel);
+    }
+}
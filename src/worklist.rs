@@ -0,0 +1,257 @@
+use crate::pool::{Pool, Task};
+
+use crossbeam_deque::{Injector, Stealer, Worker};
+use parking_lot::{Condvar, Mutex};
+use std::hint;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Number of empty polls before an idle participant starts yielding its thread
+const IDLE_SPINS: usize = 64;
+
+/// Number of empty polls before an idle participant hands its thread back
+/// to the pool, a later push brings a participant back in
+const IDLE_POLLS: usize = 256;
+
+/// Lets a worklist closure queue newly discovered work
+pub struct Pusher<'a, T> {
+    local: &'a Worker<T>,
+    /// Starts another participant if a deque is free
+    spawn: &'a (dyn Fn() + Sync),
+}
+
+impl<'a, T> Pusher<'a, T> {
+    /// Queues `item` on the current worker, idle workers may steal it
+    #[inline]
+    pub fn push(&self, item: T) {
+        self.local.push(item);
+        (self.spawn)();
+    }
+}
+
+/// State of one worklist, borrowed by every participant
+struct Shared<'a, T, F> {
+    pool: &'a Pool,
+    func: &'a F,
+    injector: Injector<T>,
+    locals: Vec<Worker<T>>,
+    stealers: Vec<Stealer<T>>,
+    /// Deques not held by a participant
+    free: Mutex<Vec<usize>>,
+    /// Participants running or queued, never more than there are deques.
+    /// Only a running participant raises it, so once it drops to zero no
+    /// work is left anywhere.
+    active: AtomicUsize,
+    /// Signalled when active drops to zero. Shared through an Arc since
+    /// the last participant still holds the lock when the caller wakes.
+    idle: Arc<(Mutex<()>, Condvar)>,
+}
+
+/// Safe because a deque in locals is only touched by the participant which
+/// took its index from free, everything else is Sync
+unsafe impl<'a, T: Send, F: Sync> Sync for Shared<'a, T, F> {}
+
+impl<'a, T, F> Shared<'a, T, F>
+where
+    T: Send,
+    F: Fn(T, &Pusher<T>) + Send + Sync,
+{
+    /// Queues another participant on the pool unless every deque is taken
+    fn spawn(&self) {
+        let slots = self.locals.len();
+        let reserved = self
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                if active < slots {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            });
+        if reserved.is_ok() {
+            let task: Box<dyn FnOnce() + Send + '_> = Box::new(move || self.participate());
+            // Pool::worklist waits for active to drop to zero, which this
+            // participant only allows once it is done with self
+            let task: Task = unsafe { mem::transmute(task) };
+            self.pool.send_task(task);
+        }
+    }
+
+    /// Runs items until none turned up for a while. Expects active to
+    /// already count this participant.
+    fn participate(&self) {
+        // active never exceeds the number of deques, so one is free
+        let slot = Slot {
+            shared: self,
+            index: self.free.lock().pop().unwrap(),
+        };
+        let local = &self.locals[slot.index];
+        let spawn = || self.spawn();
+        let pusher = Pusher {
+            local,
+            spawn: &spawn,
+        };
+
+        let mut idle = 0;
+        while idle < IDLE_POLLS {
+            match find_work(local, &self.injector, &self.stealers) {
+                Some(item) => {
+                    idle = 0;
+                    (self.func)(item, &pusher);
+                }
+                None => {
+                    idle += 1;
+                    if idle > IDLE_SPINS {
+                        thread::yield_now();
+                    } else {
+                        hint::spin_loop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blocks until every participant returned
+    fn wait_idle(&self) {
+        let (lock, cvar) = &*self.idle;
+        let mut idle = lock.lock();
+        while self.active.load(Ordering::Acquire) != 0 {
+            cvar.wait(&mut idle);
+        }
+    }
+}
+
+/// Deque held by a participant, returned even if `func` panics
+struct Slot<'s, 'a, T, F> {
+    shared: &'s Shared<'a, T, F>,
+    index: usize,
+}
+
+impl<'s, 'a, T, F> Drop for Slot<'s, 'a, T, F> {
+    fn drop(&mut self) {
+        // The deque is empty, nothing gets pushed to it until it is taken again
+        self.shared.free.lock().push(self.index);
+
+        let idle = self.shared.idle.clone();
+        let (lock, cvar) = &*idle;
+        let _idle = lock.lock();
+        if self.shared.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            cvar.notify_all();
+        }
+    }
+}
+
+/// Waits for the participants even if the calling thread panics
+struct WaitIdle<'s, 'a, T, F>(&'s Shared<'a, T, F>)
+where
+    T: Send,
+    F: Fn(T, &Pusher<T>) + Send + Sync;
+
+impl<'s, 'a, T, F> Drop for WaitIdle<'s, 'a, T, F>
+where
+    T: Send,
+    F: Fn(T, &Pusher<T>) + Send + Sync,
+{
+    fn drop(&mut self) {
+        self.0.wait_idle();
+    }
+}
+
+impl Pool {
+    /// Runs `func` on every item of `initial` and on every item pushed while
+    /// running, until no work is left. Each participant keeps its own deque
+    /// and steals from the others once it runs dry. Idle participants hand
+    /// their thread back to the pool, so `func` may use the pool itself.
+    pub fn worklist<T, F>(&self, initial: Vec<T>, func: F)
+    where
+        T: Send,
+        F: Fn(T, &Pusher<T>) + Send + Sync,
+    {
+        if initial.is_empty() {
+            return;
+        }
+
+        // One deque per worker plus one for the calling thread
+        let slots = self.num_threads + 1;
+        let locals: Vec<Worker<T>> = (0..slots).map(|_| Worker::new_lifo()).collect();
+        let stealers = locals.iter().map(Worker::stealer).collect();
+        let helpers = initial.len().min(slots) - 1;
+        let injector = Injector::new();
+        for item in initial {
+            injector.push(item);
+        }
+
+        let shared = Shared {
+            pool: self,
+            func: &func,
+            injector,
+            locals,
+            stealers,
+            free: Mutex::new((0..slots).collect()),
+            // The calling thread
+            active: AtomicUsize::new(1),
+            idle: Arc::new((Mutex::new(()), Condvar::new())),
+        };
+        let _wait = WaitIdle(&shared);
+        for _ in 0..helpers {
+            shared.spawn();
+        }
+        shared.participate();
+    }
+}
+
+#[inline]
+fn find_work<T>(local: &Worker<T>, injector: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
+    local.pop().or_else(|| loop {
+        let stolen = injector
+            .steal_batch_and_pop(local)
+            .or_else(|| stealers.iter().map(Stealer::steal).collect());
+        if !stolen.is_retry() {
+            return stolen.success();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worklist() {
+        let pool = Pool::new(4);
+        let limit = 100_000;
+        let seen: Vec<AtomicUsize> = (0..limit).map(|_| AtomicUsize::new(0)).collect();
+
+        pool.worklist(vec![0usize], |node, pusher| {
+            seen[node].fetch_add(1, Ordering::Relaxed);
+            for child in [2 * node + 1, 2 * node + 2].iter() {
+                if *child < limit {
+                    pusher.push(*child);
+                }
+            }
+        });
+        assert!(seen.iter().all(|count| count.load(Ordering::Relaxed) == 1));
+
+        pool.worklist(Vec::new(), |_: usize, _| unreachable!());
+    }
+
+    #[test]
+    fn test_worklist_nested() {
+        let pool = Pool::new(4);
+        let inputs = [1usize; 100];
+        let total = AtomicUsize::new(0);
+
+        // Binary tree of depth 6, every node maps on the same pool
+        pool.worklist(vec![0usize], |depth, pusher| {
+            let sum: usize = pool.map(&inputs, |val| val * 2).into_iter().sum();
+            total.fetch_add(sum, Ordering::Relaxed);
+            if depth < 5 {
+                pusher.push(depth + 1);
+                pusher.push(depth + 1);
+            }
+        });
+        assert_eq!(total.load(Ordering::Relaxed), 63 * 200);
+    }
+}